@@ -1,21 +1,90 @@
-use std::{collections::VecDeque, time::SystemTime};
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
 
 use chrono::{DateTime, Local, Utc};
 use ds_libs::Application;
 use serde::{Deserialize, Serialize};
-use tui::widgets::ListItem;
+use tui::{
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
+    widgets::ListItem,
+};
 
+use crate::{
+    attachment::{AttachmentInfo, Attachments},
+    persistence::{Entry, Journal, SharedJournal},
+    plugins::{CommandHandler, Plugins},
+    stats::{Activity, ChatStats, Presence},
+};
+
+pub mod attachment;
+pub mod cli;
 pub mod context;
+pub mod export;
+pub mod markup;
+pub mod persistence;
+pub mod plugins;
+pub mod stats;
 
 /// The maximum number of chat messages to keep in the history.
 pub const MAX_CHAT_MESSAGES: usize = 10;
+/// The maximum number of [MessageKind::Notice] messages to keep, apart from the history.
+pub const MAX_NOTICES: usize = 5;
 pub const MAX_MESSAGE_SIZE: usize = 100;
+/// The maximum number of options a single poll can have.
+pub const MAX_POLL_OPTIONS: usize = 8;
+/// The longest bar drawn for a poll option.
+const MAX_POLL_BAR: usize = 20;
+/// The number of post ids remembered to ignore posts that are sent again.
+pub const MAX_RECENT_POST_IDS: usize = 1024;
+/// The sender of the messages posted by the server, like the replies to commands.
+pub const SYSTEM_SENDER: &str = "system";
+/// The longest name a user can rename themselves to.
+pub const MAX_NAME_LENGTH: usize = 32;
 
 /// The backend data for a basic chat app.
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct ChatApp {
     pub(crate) messages: VecDeque<Message>,
-    update_id: usize,
+    /// The newest notices, oldest first, each with the number of messages posted before it. They're
+    /// kept apart so that they don't push posts out of the history.
+    notices: VecDeque<(u64, Message)>,
+    /// The number of messages ever posted to the history, which orders them among the notices.
+    posted: u64,
+    update_id: UpdateId,
+    next_poll_id: usize,
+    attachments: Attachments,
+    /// The ids of the newest posts, oldest first.
+    recent_post_ids: VecDeque<u64>,
+    activity: Activity,
+    #[serde(skip)]
+    presence: Presence,
+    #[serde(skip, default = "Plugins::with_builtins")]
+    plugins: Plugins,
+    #[serde(skip)]
+    journal: SharedJournal,
+}
+
+/// Identifies a version of the chat's history. The sequence number counts the changes to the
+/// history, and the epoch is picked at random when a chat is created so that the sequence numbers
+/// of a restarted server can't be mistaken for ones from before the restart.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct UpdateId {
+    pub epoch: u64,
+    pub seq: usize,
+}
+
+impl UpdateId {
+    /// Whether a history with this id should replace one with the other id.
+    pub fn is_newer_than(self, other: UpdateId) -> bool {
+        self.epoch != other.epoch || self.seq > other.seq
+    }
 }
 
 /// One message.
@@ -24,27 +93,208 @@ pub struct Message {
     pub text: String,
     pub sent_time: DateTime<Utc>,
     pub sender: String,
+    /// A unique id chosen by the client. A post with the same id as one of the last
+    /// [MAX_RECENT_POST_IDS] posts is ignored, so a client can safely send a post again after a
+    /// crash or reconnect.
+    pub id: Option<u64>,
+    /// The poll that this message is asking, if any.
+    pub poll: Option<Poll>,
+    /// The file that was shared with this message, if any.
+    pub attachment: Option<AttachmentInfo>,
+    /// Who wrote the message. Only the server can post [MessageKind::System] messages.
+    #[serde(default)]
+    pub kind: MessageKind,
+}
+
+/// Whether a message was posted by a user or by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum MessageKind {
+    User,
+    /// Posted by the server, like the replies to server commands.
+    System,
+    /// Posted by the server about users joining, going idle or changing their names.
+    Notice,
+}
+
+impl Default for MessageKind {
+    fn default() -> Self {
+        MessageKind::User
+    }
+}
+
+/// A question with a fixed set of options that users can vote on.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Poll {
+    pub id: usize,
+    pub options: Vec<PollOption>,
+    pub closed: bool,
+}
+
+/// One of the choices in a [Poll] and the users that voted for it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct PollOption {
+    pub text: String,
+    pub voters: BTreeSet<String>,
 }
 
 impl<'a> From<Message> for ListItem<'a> {
     fn from(val: Message) -> Self {
-        ListItem::new(format!(
-            "{} - {}: {}",
+        // Messages from the server are shown as written, so that they stand out from user posts.
+        if val.kind != MessageKind::User {
+            let style = Style::default()
+                .fg(Color::DarkGray)
+                .add_modifier(Modifier::ITALIC);
+            let time = val.sent_time.with_timezone(&Local).format("%I:%M%P");
+            return ListItem::new(
+                val.text
+                    .split('\n')
+                    .enumerate()
+                    .map(|(i, line)| {
+                        let line = if i == 0 {
+                            format!("{} * {}", time, line)
+                        } else {
+                            line.to_string()
+                        };
+                        Spans::from(Span::styled(line, style))
+                    })
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        let header = Span::raw(format!(
+            "{} - {}: ",
             val.sent_time.with_timezone(&Local).format("%I:%M%P"),
             val.sender,
-            val.text
-        ))
+        ));
+
+        // The header goes in front of the first line of the message.
+        let mut lines: Vec<_> = markup::parse_or_literal(&val.text)
+            .into_iter()
+            .map(|line| Spans::from(line.into_iter().map(Span::from).collect::<Vec<_>>()))
+            .collect();
+        lines[0].0.insert(0, header);
+
+        let mut tags = String::new();
+        if let Some(attachment) = &val.attachment {
+            tags += &format!(
+                " [file #{}: {}, {} bytes]",
+                attachment.id, attachment.name, attachment.size
+            );
+        }
+        if let Some(poll) = &val.poll {
+            tags += &format!(
+                " [poll #{}{}]",
+                poll.id,
+                if poll.closed { ", closed" } else { "" }
+            );
+        }
+        if !tags.is_empty() {
+            lines.last_mut().unwrap().0.push(Span::raw(tags));
+        }
+
+        if let Some(poll) = val.poll {
+            // Shrink the bars when the most popular option would not fit.
+            let most_votes = poll.options.iter().map(|o| o.voters.len()).max();
+            let scale = most_votes.unwrap_or(0).max(MAX_POLL_BAR);
+            for (i, option) in poll.options.iter().enumerate() {
+                let votes = option.voters.len();
+                lines.push(Spans::from(format!(
+                    "    {}. {:<20} {} {}",
+                    i + 1,
+                    option.text,
+                    "\u{2588}".repeat(votes * MAX_POLL_BAR / scale),
+                    votes
+                )));
+            }
+        }
+
+        ListItem::new(lines)
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum ChatCommand {
-    /// Post the given message in the chat.
+    /// Post the given message in the chat. Messages starting with `/` run the server command
+    /// with that name instead, start a message with `//` to post it with a single `/`.
     Post(Message),
-    /// Get the history of the chat. Will only return up to [MAX_CHAT_MESSAGES]. If the
-    /// the id is the same as the servers, there are no new messages and [ChatResponse::NoUpdate]
-    /// will be returned.
-    GetLatest(usize),
+    /// Get the history of the chat. Will only return up to [MAX_CHAT_MESSAGES], and the notices
+    /// among them up to [MAX_NOTICES]. If the id is the same as the server's, there are no new
+    /// messages and [ChatResponse::NoUpdate] will be returned.
+    GetLatest(UpdateId),
+    /// Post the message like [ChatCommand::Post], but only if the id is still the server's latest
+    /// update id. Otherwise nothing is posted and the latest messages are returned like
+    /// [ChatCommand::GetLatest], so the sender can decide whether to post again.
+    PostIfLatest(Message, UpdateId),
+    /// Post a new poll asking the question with the given options. Only the session that is
+    /// connected with the sender's name can post polls as them.
+    CreatePoll {
+        sender: String,
+        question: String,
+        options: Vec<String>,
+        session: u64,
+    },
+    /// Vote for an option in a poll. Voting again replaces the voter's previous vote. Only the
+    /// session that is connected with the voter's name can vote as them. This keeps anyone from
+    /// voting as someone else, but not from voting again under a new name.
+    Vote {
+        poll: usize,
+        option: usize,
+        voter: String,
+        session: u64,
+    },
+    /// Stop accepting votes for a poll. Only the creator of the poll can close it, from the
+    /// session that is connected with their name.
+    ClosePoll {
+        poll: usize,
+        sender: String,
+        session: u64,
+    },
+    /// Start uploading a file. The server responds with the id to send the chunks to.
+    StartUpload {
+        sender: String,
+        name: String,
+        size: usize,
+        checksum: u32,
+    },
+    /// Send one [attachment::CHUNK_SIZE] piece of a file. Only the last chunk can be shorter.
+    UploadChunk {
+        upload: usize,
+        index: usize,
+        data: Vec<u8>,
+    },
+    /// Verify and store an upload once all of its chunks are sent, then post the given text with
+    /// the file attached.
+    FinishUpload { upload: usize, text: String },
+    /// Get one chunk of a stored attachment.
+    GetChunk { attachment: usize, index: usize },
+    /// Get the [ChatStats] of the chat.
+    Stats,
+    /// Tell the server that the client is still connected. Clients send this with their polls.
+    /// The first heartbeat with a name, or the first after going idle, announces that the user
+    /// joined. The session is picked at random when the client starts, and while it's connected
+    /// nobody else can use the name, or rename it.
+    Heartbeat { name: String, session: u64 },
+    /// Change the name that a user is known by and announce it in the chat. Only the session
+    /// that is connected with the old name can change it.
+    Rename {
+        from: String,
+        to: String,
+        session: u64,
+    },
+    /// Run the commands in order as one command. If any of them fails none of them are applied.
+    /// Batches can't contain other batches.
+    Batch(Vec<ChatCommand>),
+}
+
+impl ChatCommand {
+    /// Whether the command never changes the chat.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            ChatCommand::GetLatest(_) | ChatCommand::GetChunk { .. } | ChatCommand::Stats => true,
+            ChatCommand::Batch(commands) => commands.iter().all(ChatCommand::is_read_only),
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
@@ -52,19 +302,670 @@ pub enum ChatResponse {
     /// The post was successful.
     PostOk,
     /// The current history of the chat.
-    Latest(Vec<Message>, usize),
+    Latest(Vec<Message>, UpdateId),
     /// The history matches what you already have.
     NoUpdate,
+    /// The vote was recorded.
+    VoteOk,
+    /// The poll was closed.
+    PollClosed,
+    /// The upload was started, send its chunks to the given id.
+    UploadStarted(usize),
+    /// The chunk was stored.
+    ChunkOk,
+    /// One chunk of an attachment.
+    Chunk(AttachmentInfo, Vec<u8>),
+    /// The current stats of the chat.
+    Stats(ChatStats),
+    /// The heartbeat was recorded.
+    HeartbeatOk,
+    /// The user is now known by this name.
+    Renamed(String),
+    /// The responses to each of the commands in a [ChatCommand::Batch].
+    Batch(Vec<ChatResponse>),
+    /// The command could not be applied.
+    Error(ChatError),
 }
 
+/// The reasons a [ChatCommand] can be rejected.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum ChatError {
+    /// The poll does not exist or is no longer in the history.
+    UnknownPoll(usize),
+    /// The option is not one of the poll's options.
+    InvalidPollOption(usize),
+    /// A poll needs between 2 and [MAX_POLL_OPTIONS] options.
+    InvalidPollOptionCount(usize),
+    /// The poll is not accepting votes anymore.
+    PollClosed(usize),
+    /// Only the creator of a poll can close it.
+    NotPollOwner(usize),
+    /// The file is larger than [attachment::MAX_ATTACHMENT_SIZE].
+    AttachmentTooLarge(usize),
+    /// The file name is empty or longer than [attachment::MAX_NAME_LENGTH].
+    InvalidAttachmentName,
+    /// The upload does not exist or was abandoned.
+    UnknownUpload(usize),
+    /// The attachment does not exist or was dropped to make room for newer ones.
+    UnknownAttachment(usize),
+    /// The chunk is out of range or has the wrong size.
+    InvalidChunk(usize),
+    /// The upload can't be finished before this chunk is sent.
+    MissingChunk(usize),
+    /// The reassembled file does not match the checksum it was started with.
+    ChecksumMismatch(usize),
+    /// There is no server command with this name.
+    UnknownCommand(String),
+    /// The server command rejected its arguments.
+    CommandFailed(String),
+    /// The command at this index in the batch failed, so the batch was not applied.
+    BatchFailed(usize, Box<ChatError>),
+    /// Names must be 1 to [MAX_NAME_LENGTH] characters without whitespace, and can't be the
+    /// [SYSTEM_SENDER].
+    InvalidName(String),
+    /// Someone who is connected is already using the name.
+    NameTaken(String),
+    /// The change was made but it couldn't be saved, so it may be lost when the server restarts.
+    NotSaved(String),
+    /// The name isn't connected in the session that tried to change it.
+    NotConnected(String),
+    /// A [ChatCommand::Batch] contained another batch.
+    NestedBatch,
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatError::UnknownPoll(id) => write!(f, "poll #{} does not exist", id),
+            ChatError::InvalidPollOption(option) => {
+                write!(f, "option {} is not part of the poll", option + 1)
+            }
+            ChatError::InvalidPollOptionCount(count) => write!(
+                f,
+                "a poll needs between 2 and {} options, got {}",
+                MAX_POLL_OPTIONS, count
+            ),
+            ChatError::PollClosed(id) => write!(f, "poll #{} is closed", id),
+            ChatError::NotPollOwner(id) => {
+                write!(f, "only the creator of poll #{} can close it", id)
+            }
+            ChatError::AttachmentTooLarge(size) => write!(
+                f,
+                "files can be at most {} bytes, got {}",
+                attachment::MAX_ATTACHMENT_SIZE,
+                size
+            ),
+            ChatError::InvalidAttachmentName => write!(
+                f,
+                "file names must be between 1 and {} bytes",
+                attachment::MAX_NAME_LENGTH
+            ),
+            ChatError::UnknownUpload(id) => write!(f, "upload #{} does not exist", id),
+            ChatError::UnknownAttachment(id) => write!(f, "file #{} does not exist", id),
+            ChatError::InvalidChunk(index) => write!(f, "chunk {} is invalid", index),
+            ChatError::MissingChunk(index) => write!(f, "chunk {} was never uploaded", index),
+            ChatError::ChecksumMismatch(id) => {
+                write!(f, "upload #{} does not match its checksum", id)
+            }
+            ChatError::UnknownCommand(name) => {
+                write!(f, "/{} is not a command, try /help", name)
+            }
+            ChatError::CommandFailed(reason) => write!(f, "{}", reason),
+            ChatError::BatchFailed(index, e) => {
+                write!(f, "command {} of the batch failed: {}", index + 1, e)
+            }
+            ChatError::InvalidName(name) => write!(
+                f,
+                "{} is not a valid name, names must be 1-{} characters without spaces",
+                name, MAX_NAME_LENGTH
+            ),
+            ChatError::NameTaken(name) => write!(f, "{} is already in the chat", name),
+            ChatError::NotSaved(reason) => write!(f, "the change could not be saved: {}", reason),
+            ChatError::NotConnected(name) => write!(f, "you are not connected as {}", name),
+            ChatError::NestedBatch => write!(f, "batches can't contain other batches"),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
+
 impl ChatApp {
     /// Construct an empty chat.
     pub fn new() -> ChatApp {
         ChatApp {
             messages: VecDeque::with_capacity(MAX_CHAT_MESSAGES),
-            update_id: 0,
+            notices: VecDeque::with_capacity(MAX_NOTICES),
+            posted: 0,
+            update_id: UpdateId {
+                epoch: rand::random(),
+                seq: 0,
+            },
+            next_poll_id: 0,
+            attachments: Attachments::default(),
+            recent_post_ids: VecDeque::new(),
+            activity: Activity::default(),
+            presence: Presence::default(),
+            plugins: Plugins::with_builtins(),
+            journal: SharedJournal::default(),
+        }
+    }
+
+    /// Construct a chat that starts with the given history, like one that was exported from
+    /// another chat. Only the newest [MAX_CHAT_MESSAGES] are kept. The files of attachments are
+    /// not part of the history, so attachments are dropped.
+    pub fn from_history(history: Vec<Message>) -> ChatApp {
+        let mut out = ChatApp::new();
+
+        for mut message in history {
+            message.attachment = None;
+            if let Some(poll) = &message.poll {
+                out.next_poll_id = out.next_poll_id.max(poll.id + 1);
+            }
+            let time = message.sent_time;
+            out.push_message(message, time);
         }
+
+        out
+    }
+
+    /// The messages that are currently kept, oldest first. Notices aren't part of the history.
+    pub fn history(&self) -> &VecDeque<Message> {
+        &self.messages
+    }
+
+    /// The history and the notices, in the order the server got them, as they are sent to the
+    /// clients. The times on posts come from the clients, so they can't be trusted to order them.
+    fn latest(&self) -> Vec<Message> {
+        let mut latest = Vec::with_capacity(self.messages.len() + self.notices.len());
+        let mut notices = self.notices.iter().peekable();
+        let first = self.posted - self.messages.len() as u64;
+        for (i, message) in self.messages.iter().enumerate() {
+            while let Some((_, notice)) = notices
+                .peek()
+                .filter(|(before, _)| *before <= first + i as u64)
+            {
+                latest.push(notice.clone());
+                notices.next();
+            }
+            latest.push(message.clone());
+        }
+        latest.extend(notices.map(|(_, notice)| notice.clone()));
+        latest
+    }
+
+    /// Add a server command that users can run by posting `/<name>`.
+    pub fn register_command<H>(&mut self, handler: H)
+    where
+        H: CommandHandler + 'static,
+    {
+        self.plugins.register(handler);
     }
+
+    /// Send every command that changes the chat to the journal, so that the chat can be rebuilt
+    /// after a restart.
+    pub fn set_journal(&mut self, journal: Arc<Mutex<dyn Journal>>) {
+        self.journal = SharedJournal(Some(journal));
+    }
+
+    /// Apply a command read back from a journal.
+    pub fn replay(&mut self, entry: Entry) {
+        self.execute(entry.command, entry.time, true);
+    }
+
+    /// Apply a command as if it was run at the given time. Returns the response and, if the
+    /// command changed the chat, the command that makes the same change when replayed.
+    fn execute(
+        &mut self,
+        command: ChatCommand,
+        time: DateTime<Utc>,
+        replaying: bool,
+    ) -> (ChatResponse, Option<ChatCommand>) {
+        let change = match command {
+            // Posts are journaled after any server command in them is run.
+            ChatCommand::Post(post) => {
+                return match self.post(post, time, replaying) {
+                    Ok(post) => (ChatResponse::PostOk, post.map(ChatCommand::Post)),
+                    Err(e) => (ChatResponse::Error(e), None),
+                };
+            }
+            ChatCommand::PostIfLatest(post, id) => {
+                // A resent post that was already applied still succeeds.
+                let posted =
+                    matches!(post.id, Some(post_id) if self.recent_post_ids.contains(&post_id));
+                if id != self.update_id && !posted {
+                    return (ChatResponse::Latest(self.latest(), self.update_id), None);
+                }
+                return self.execute(ChatCommand::Post(post), time, replaying);
+            }
+            // Presence isn't saved, only the notices about it are.
+            ChatCommand::Heartbeat { name, session } => {
+                if !is_valid_name(&name) {
+                    return (ChatResponse::Error(ChatError::InvalidName(name)), None);
+                }
+                if matches!(self.presence.session(&name), Some(other) if other != session) {
+                    return (ChatResponse::Error(ChatError::NameTaken(name)), None);
+                }
+                let change = if self.presence.heartbeat(name.clone(), session) {
+                    Some(self.post_event(format!("{} joined the chat", name), time))
+                } else {
+                    None
+                };
+                return (ChatResponse::HeartbeatOk, change);
+            }
+            ChatCommand::Rename { from, to, session } => {
+                return match self.rename(from, to.clone(), session, time) {
+                    Ok(change) => (ChatResponse::Renamed(to), Some(change)),
+                    Err(e) => (ChatResponse::Error(e), None),
+                };
+            }
+            // Batches are journaled as the changes of their commands.
+            ChatCommand::Batch(commands) => return self.batch(commands, time, replaying),
+            _ if command.is_read_only() => None,
+            _ => Some(command.clone()),
+        };
+
+        let response = match command {
+            ChatCommand::Post(_)
+            | ChatCommand::PostIfLatest(..)
+            | ChatCommand::Heartbeat { .. }
+            | ChatCommand::Rename { .. }
+            | ChatCommand::Batch(_) => unreachable!("These are handled above"),
+            ChatCommand::GetLatest(id) => {
+                if id == self.update_id {
+                    ChatResponse::NoUpdate
+                } else {
+                    ChatResponse::Latest(self.latest(), self.update_id)
+                }
+            }
+            ChatCommand::CreatePoll {
+                sender,
+                question,
+                options,
+                session,
+            } => self
+                .create_poll(sender, question, options, session, time, replaying)
+                .unwrap_or_else(ChatResponse::Error),
+            ChatCommand::Vote {
+                poll,
+                option,
+                voter,
+                session,
+            } => self
+                .vote(poll, option, voter, session, replaying)
+                .unwrap_or_else(ChatResponse::Error),
+            ChatCommand::ClosePoll {
+                poll,
+                sender,
+                session,
+            } => self
+                .close_poll(poll, sender, session, replaying)
+                .unwrap_or_else(ChatResponse::Error),
+            ChatCommand::StartUpload {
+                sender,
+                name,
+                size,
+                checksum,
+            } => self
+                .attachments
+                .start(sender, name, size, checksum)
+                .map_or_else(ChatResponse::Error, ChatResponse::UploadStarted),
+            ChatCommand::UploadChunk {
+                upload,
+                index,
+                data,
+            } => self
+                .attachments
+                .add_chunk(upload, index, data)
+                .map_or_else(ChatResponse::Error, |_| ChatResponse::ChunkOk),
+            ChatCommand::FinishUpload { upload, text } => self
+                .finish_upload(upload, text, time)
+                .unwrap_or_else(ChatResponse::Error),
+            ChatCommand::GetChunk { attachment, index } => self
+                .attachments
+                .chunk(attachment, index)
+                .map_or_else(ChatResponse::Error, |(info, data)| {
+                    ChatResponse::Chunk(info, data)
+                }),
+            ChatCommand::Stats => {
+                ChatResponse::Stats(ChatStats::new(&self.activity, &self.presence))
+            }
+        };
+
+        (response, change)
+    }
+
+    fn batch(
+        &mut self,
+        commands: Vec<ChatCommand>,
+        time: DateTime<Utc>,
+        replaying: bool,
+    ) -> (ChatResponse, Option<ChatCommand>) {
+        if let Some(i) = commands
+            .iter()
+            .position(|c| matches!(c, ChatCommand::Batch(_)))
+        {
+            let error = ChatError::BatchFailed(i, Box::new(ChatError::NestedBatch));
+            return (ChatResponse::Error(error), None);
+        }
+
+        // Only keep a copy to roll back to if the batch could change the chat and then fail.
+        // Heartbeats fail before they change anything, so the polls that clients send, a heartbeat
+        // and reads that can't fail, don't need one.
+        let can_fail =
+            |c: &ChatCommand| !matches!(c, ChatCommand::GetLatest(_) | ChatCommand::Stats);
+        let needs_rollback = commands.iter().enumerate().any(|(i, c)| match c {
+            ChatCommand::Heartbeat { .. } => commands[i + 1..].iter().any(can_fail),
+            c => !c.is_read_only(),
+        });
+        let before = if needs_rollback {
+            Some(Rollback::new(self))
+        } else {
+            None
+        };
+
+        let mut responses = Vec::with_capacity(commands.len());
+        let mut changes = vec![];
+        for (i, command) in commands.into_iter().enumerate() {
+            match self.execute(command, time, replaying) {
+                (ChatResponse::Error(e), _) => {
+                    let error = ChatResponse::Error(ChatError::BatchFailed(i, Box::new(e)));
+                    // Without a copy, nothing was changed before the failure.
+                    if let Some(before) = before {
+                        before.restore(self);
+                    }
+                    return (error, None);
+                }
+                (response, change) => {
+                    responses.push(response);
+                    changes.extend(change);
+                }
+            }
+        }
+
+        (ChatResponse::Batch(responses), batch_change(changes))
+    }
+
+    /// Post a message, or the reply to the server command in it. Returns the message that was
+    /// posted, or nothing if the post was already posted.
+    fn post(
+        &mut self,
+        mut post: Message,
+        time: DateTime<Utc>,
+        replaying: bool,
+    ) -> Result<Option<Message>, ChatError> {
+        if let Some(id) = post.id {
+            if self.recent_post_ids.contains(&id) {
+                return Ok(None);
+            }
+        }
+
+        // Polls and attachments are only added by their own commands.
+        post.poll = None;
+        post.attachment = None;
+
+        // Replayed posts already had their commands run.
+        if !replaying {
+            // Users can't post notices as the server.
+            post.kind = MessageKind::User;
+
+            if post.text.starts_with("//") {
+                post.text.remove(0);
+            } else if post.text.starts_with('/') {
+                let reply = self.plugins.dispatch(&post.sender, &post.text)?;
+                // Keep the id so that the command isn't run again either.
+                post = Message {
+                    id: post.id,
+                    ..Message::system(reply, time)
+                };
+            }
+        }
+
+        if let Some(id) = post.id {
+            if self.recent_post_ids.len() >= MAX_RECENT_POST_IDS {
+                self.recent_post_ids.pop_front();
+            }
+            self.recent_post_ids.push_back(id);
+        }
+
+        self.push_message(post.clone(), time);
+
+        Ok(Some(post))
+    }
+
+    /// Post a notice from the server. Returns the change to journal for it.
+    fn post_event(&mut self, text: String, time: DateTime<Utc>) -> ChatCommand {
+        let message = Message {
+            kind: MessageKind::Notice,
+            ..Message::system(text, time)
+        };
+        self.push_message(message.clone(), time);
+        ChatCommand::Post(message)
+    }
+
+    fn rename(
+        &mut self,
+        from: String,
+        to: String,
+        session: u64,
+        time: DateTime<Utc>,
+    ) -> Result<ChatCommand, ChatError> {
+        if self.presence.session(&from) != Some(session) {
+            return Err(ChatError::NotConnected(from));
+        }
+        if !is_valid_name(&to) {
+            return Err(ChatError::InvalidName(to));
+        }
+        if to != from && self.presence.session(&to).is_some() {
+            return Err(ChatError::NameTaken(to));
+        }
+
+        self.presence.rename(&from, to.clone());
+        Ok(self.post_event(format!("{} is now known as {}", from, to), time))
+    }
+
+    /// Add a message to the end of the history, dropping the oldest message if the history is
+    /// full. Notices go to their own list instead. The time is when the server got the message,
+    /// which the stats count it at.
+    fn push_message(&mut self, message: Message, time: DateTime<Utc>) {
+        if message.kind == MessageKind::Notice {
+            if self.notices.len() >= MAX_NOTICES {
+                self.notices.pop_front();
+            }
+            self.notices.push_back((self.posted, message));
+            self.update_id.seq += 1;
+            return;
+        }
+
+        // Check if we have too many chat messages
+        if self.messages.len() >= MAX_CHAT_MESSAGES {
+            self.messages.pop_front();
+        }
+
+        self.activity.count(&message, time);
+        self.messages.push_back(message);
+        self.posted += 1;
+        self.update_id.seq += 1;
+    }
+
+    /// Find the message in the history that holds the given poll.
+    fn find_poll(&mut self, id: usize) -> Result<&mut Message, ChatError> {
+        self.messages
+            .iter_mut()
+            .find(|m| m.poll.as_ref().map(|p| p.id) == Some(id))
+            .ok_or(ChatError::UnknownPoll(id))
+    }
+
+    fn create_poll(
+        &mut self,
+        sender: String,
+        question: String,
+        options: Vec<String>,
+        session: u64,
+        time: DateTime<Utc>,
+        replaying: bool,
+    ) -> Result<ChatResponse, ChatError> {
+        if !self.is_connected(&sender, session, replaying) {
+            return Err(ChatError::NotConnected(sender));
+        }
+        if options.len() < 2 || options.len() > MAX_POLL_OPTIONS {
+            return Err(ChatError::InvalidPollOptionCount(options.len()));
+        }
+
+        let poll = Poll {
+            id: self.next_poll_id,
+            options: options
+                .into_iter()
+                .map(|text| PollOption {
+                    text,
+                    voters: BTreeSet::new(),
+                })
+                .collect(),
+            closed: false,
+        };
+        self.next_poll_id += 1;
+
+        let mut message = Message::new(sender, question);
+        message.sent_time = time;
+        message.poll = Some(poll);
+        self.push_message(message, time);
+
+        Ok(ChatResponse::PostOk)
+    }
+
+    /// Whether the name is connected in the session. Presence isn't saved, so the commands that
+    /// are replayed were already checked.
+    fn is_connected(&self, name: &str, session: u64, replaying: bool) -> bool {
+        replaying || self.presence.session(name) == Some(session)
+    }
+
+    /// Vote as the voter, who has to be connected in the session.
+    fn vote(
+        &mut self,
+        id: usize,
+        option: usize,
+        voter: String,
+        session: u64,
+        replaying: bool,
+    ) -> Result<ChatResponse, ChatError> {
+        let connected = self.is_connected(&voter, session, replaying);
+        let poll = self.find_poll(id)?.poll.as_mut().unwrap();
+
+        if poll.closed {
+            return Err(ChatError::PollClosed(id));
+        }
+        if option >= poll.options.len() {
+            return Err(ChatError::InvalidPollOption(option));
+        }
+        if !connected {
+            return Err(ChatError::NotConnected(voter));
+        }
+
+        // Each user only gets one vote, so drop any previous one.
+        for o in poll.options.iter_mut() {
+            o.voters.remove(&voter);
+        }
+        poll.options[option].voters.insert(voter);
+        self.update_id.seq += 1;
+
+        Ok(ChatResponse::VoteOk)
+    }
+
+    /// Close the poll for its creator, who has to be connected in the session.
+    fn close_poll(
+        &mut self,
+        id: usize,
+        sender: String,
+        session: u64,
+        replaying: bool,
+    ) -> Result<ChatResponse, ChatError> {
+        let connected = self.is_connected(&sender, session, replaying);
+        let message = self.find_poll(id)?;
+
+        if message.sender != sender {
+            return Err(ChatError::NotPollOwner(id));
+        }
+        if !connected {
+            return Err(ChatError::NotConnected(sender));
+        }
+        message.poll.as_mut().unwrap().closed = true;
+        self.update_id.seq += 1;
+
+        Ok(ChatResponse::PollClosed)
+    }
+
+    fn finish_upload(
+        &mut self,
+        id: usize,
+        text: String,
+        time: DateTime<Utc>,
+    ) -> Result<ChatResponse, ChatError> {
+        let (sender, info) = self.attachments.finish(id)?;
+
+        let mut message = Message::new(sender, text);
+        message.sent_time = time;
+        message.attachment = Some(info);
+        self.push_message(message, time);
+
+        Ok(ChatResponse::PostOk)
+    }
+}
+
+/// The parts of the chat that a [ChatCommand::Batch] can change, to put back if it fails.
+struct Rollback {
+    messages: VecDeque<Message>,
+    notices: VecDeque<(u64, Message)>,
+    posted: u64,
+    update_id: UpdateId,
+    next_poll_id: usize,
+    attachments: Attachments,
+    recent_post_ids: VecDeque<u64>,
+    activity: Activity,
+    presence: Presence,
+}
+
+impl Rollback {
+    fn new(chat: &ChatApp) -> Rollback {
+        Rollback {
+            messages: chat.messages.clone(),
+            notices: chat.notices.clone(),
+            posted: chat.posted,
+            update_id: chat.update_id,
+            next_poll_id: chat.next_poll_id,
+            attachments: chat.attachments.clone(),
+            recent_post_ids: chat.recent_post_ids.clone(),
+            activity: chat.activity.clone(),
+            presence: chat.presence.clone(),
+        }
+    }
+
+    fn restore(self, chat: &mut ChatApp) {
+        chat.messages = self.messages;
+        chat.notices = self.notices;
+        chat.posted = self.posted;
+        chat.update_id = self.update_id;
+        chat.next_poll_id = self.next_poll_id;
+        chat.attachments = self.attachments;
+        chat.recent_post_ids = self.recent_post_ids;
+        chat.activity = self.activity;
+        chat.presence = self.presence;
+    }
+}
+
+/// The change to journal for the changes of the commands in a batch.
+fn batch_change(changes: Vec<ChatCommand>) -> Option<ChatCommand> {
+    if changes.is_empty() {
+        None
+    } else {
+        Some(ChatCommand::Batch(changes))
+    }
+}
+
+/// Whether users can go by the name, see [ChatError::InvalidName].
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= MAX_NAME_LENGTH
+        && !name.contains(char::is_whitespace)
+        && name != SYSTEM_SENDER
 }
 
 impl Default for ChatApp {
@@ -80,6 +981,20 @@ impl Message {
             sender,
             text,
             sent_time: SystemTime::now().into(),
+            id: None,
+            poll: None,
+            attachment: None,
+            kind: MessageKind::User,
+        }
+    }
+
+    /// Construct a [MessageKind::System] message from the server, like the reply to a server
+    /// command, sent at the given time.
+    pub fn system(text: String, time: DateTime<Utc>) -> Message {
+        Message {
+            sent_time: time,
+            kind: MessageKind::System,
+            ..Message::new(SYSTEM_SENDER.to_string(), text)
         }
     }
 }
@@ -90,26 +1005,27 @@ impl Application for ChatApp {
     type Res = ChatResponse;
 
     fn process(&mut self, request: Self::Command) -> Self::Res {
-        match request {
-            ChatCommand::Post(post) => {
-                // Check if we have too many chat messages
-                if self.messages.len() >= MAX_CHAT_MESSAGES {
-                    self.messages.pop_front();
-                }
+        let time = Utc::now();
 
-                self.messages.push_back(post);
-                self.update_id += 1;
-
-                ChatResponse::PostOk
+        // There is no timer, so users that went idle are found when the next command comes in.
+        for name in self.presence.expire() {
+            let command = self.post_event(format!("{} went idle", name), time);
+            if let Err(e) = self.journal.record(Entry { time, command }) {
+                return ChatResponse::Error(ChatError::NotSaved(e.to_string()));
             }
-            ChatCommand::GetLatest(id) => {
-                if id == self.update_id {
-                    ChatResponse::NoUpdate
-                } else {
-                    ChatResponse::Latest(self.messages.clone().into(), self.update_id)
-                }
+        }
+
+        let (response, change) = self.execute(request, time, false);
+
+        // Only acknowledge a change once it's recorded.
+        if let Some(command) = change {
+            if let Err(e) = self.journal.record(Entry { time, command }) {
+                return ChatResponse::Error(ChatError::NotSaved(e.to_string()));
             }
         }
+        self.journal.clone().checkpoint(self);
+
+        response
     }
 }
 
@@ -127,7 +1043,9 @@ mod tests {
             chat.process(ChatCommand::Post(message.clone()))
         );
 
-        if let ChatResponse::Latest(log, _) = chat.process(ChatCommand::GetLatest(0)) {
+        if let ChatResponse::Latest(log, _) =
+            chat.process(ChatCommand::GetLatest(UpdateId::default()))
+        {
             assert_eq!(1, log.len());
             assert_eq!(message, log[0]);
         } else {
@@ -145,16 +1063,18 @@ mod tests {
             chat.process(ChatCommand::Post(message.clone()))
         );
 
-        if let ChatResponse::Latest(log, _) = chat.process(ChatCommand::GetLatest(0)) {
-            assert_eq!(1, log.len());
-            assert_eq!(message, log[0]);
-        } else {
-            panic!("Failed to GetLatest");
-        }
+        let id = match chat.process(ChatCommand::GetLatest(UpdateId::default())) {
+            ChatResponse::Latest(log, id) => {
+                assert_eq!(1, log.len());
+                assert_eq!(message, log[0]);
+                id
+            }
+            _ => panic!("Failed to GetLatest"),
+        };
 
         assert_eq!(
             ChatResponse::NoUpdate,
-            chat.process(ChatCommand::GetLatest(1))
+            chat.process(ChatCommand::GetLatest(id))
         );
     }
 
@@ -174,7 +1094,9 @@ mod tests {
             chat.process(ChatCommand::Post(message2.clone()))
         );
 
-        if let ChatResponse::Latest(log, _) = chat.process(ChatCommand::GetLatest(0)) {
+        if let ChatResponse::Latest(log, _) =
+            chat.process(ChatCommand::GetLatest(UpdateId::default()))
+        {
             assert_eq!(2, log.len());
             assert_eq!(message1, log[0]);
             assert_eq!(message2, log[1]);
@@ -202,7 +1124,9 @@ mod tests {
             );
         }
 
-        if let ChatResponse::Latest(log, _) = chat.process(ChatCommand::GetLatest(0)) {
+        if let ChatResponse::Latest(log, _) =
+            chat.process(ChatCommand::GetLatest(UpdateId::default()))
+        {
             assert_eq!(MAX_CHAT_MESSAGES, log.len());
 
             assert_eq!(messages[EXTRA_MESSAGES..], log);
@@ -210,4 +1134,396 @@ mod tests {
             panic!("Failed to GetLatest");
         }
     }
+
+    fn create_poll(chat: &mut ChatApp) {
+        let command = ChatCommand::CreatePoll {
+            sender: "creator".to_string(),
+            question: "lunch?".to_string(),
+            options: vec!["pizza".to_string(), "tacos".to_string()],
+            session: 0,
+        };
+        assert_eq!(
+            ChatResponse::Error(ChatError::NotConnected("creator".to_string())),
+            chat.process(command.clone())
+        );
+        chat.process(heartbeat("creator", 0));
+        assert_eq!(ChatResponse::PostOk, chat.process(command));
+    }
+
+    fn poll_votes(chat: &mut ChatApp) -> Vec<usize> {
+        if let ChatResponse::Latest(log, _) =
+            chat.process(ChatCommand::GetLatest(UpdateId::default()))
+        {
+            let poll = log
+                .iter()
+                .find_map(|m| m.poll.as_ref())
+                .expect("The message is missing its poll");
+            poll.options.iter().map(|o| o.voters.len()).collect()
+        } else {
+            panic!("Failed to GetLatest");
+        }
+    }
+
+    #[test]
+    fn changing_a_vote() {
+        let mut chat = ChatApp::new();
+        create_poll(&mut chat);
+
+        let vote = |option| ChatCommand::Vote {
+            poll: 0,
+            option,
+            voter: "voter".to_string(),
+            session: 1,
+        };
+
+        // Only the session connected with the name can vote as it.
+        assert_eq!(
+            ChatResponse::Error(ChatError::NotConnected("voter".to_string())),
+            chat.process(vote(0))
+        );
+        chat.process(heartbeat("voter", 1));
+
+        assert_eq!(ChatResponse::VoteOk, chat.process(vote(0)));
+        assert_eq!(vec![1, 0], poll_votes(&mut chat));
+
+        assert_eq!(ChatResponse::VoteOk, chat.process(vote(1)));
+        assert_eq!(vec![0, 1], poll_votes(&mut chat));
+    }
+
+    #[test]
+    fn closing_a_poll() {
+        let mut chat = ChatApp::new();
+        create_poll(&mut chat);
+
+        assert_eq!(
+            ChatResponse::Error(ChatError::NotPollOwner(0)),
+            chat.process(ChatCommand::ClosePoll {
+                poll: 0,
+                sender: "someone else".to_string(),
+                session: 0,
+            })
+        );
+        // Only from the creator's session.
+        assert_eq!(
+            ChatResponse::Error(ChatError::NotConnected("creator".to_string())),
+            chat.process(ChatCommand::ClosePoll {
+                poll: 0,
+                sender: "creator".to_string(),
+                session: 1,
+            })
+        );
+        assert_eq!(
+            ChatResponse::PollClosed,
+            chat.process(ChatCommand::ClosePoll {
+                poll: 0,
+                sender: "creator".to_string(),
+                session: 0,
+            })
+        );
+        assert_eq!(
+            ChatResponse::Error(ChatError::PollClosed(0)),
+            chat.process(ChatCommand::Vote {
+                poll: 0,
+                option: 0,
+                voter: "voter".to_string(),
+                session: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn voting_on_an_unknown_poll() {
+        let mut chat = ChatApp::new();
+        create_poll(&mut chat);
+
+        assert_eq!(
+            ChatResponse::Error(ChatError::UnknownPoll(1)),
+            chat.process(ChatCommand::Vote {
+                poll: 1,
+                option: 0,
+                voter: "voter".to_string(),
+                session: 1,
+            })
+        );
+        assert_eq!(
+            ChatResponse::Error(ChatError::InvalidPollOption(2)),
+            chat.process(ChatCommand::Vote {
+                poll: 0,
+                option: 2,
+                voter: "voter".to_string(),
+                session: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn running_a_command() {
+        let mut chat = ChatApp::new();
+
+        assert_eq!(
+            ChatResponse::PostOk,
+            chat.process(ChatCommand::Post(Message::new(
+                "sender".to_string(),
+                "/roll 2d6".to_string()
+            )))
+        );
+        assert_eq!(
+            ChatResponse::Error(ChatError::UnknownCommand("nope".to_string())),
+            chat.process(ChatCommand::Post(Message::new(
+                "sender".to_string(),
+                "/nope".to_string()
+            )))
+        );
+        assert_eq!(
+            ChatResponse::PostOk,
+            chat.process(ChatCommand::Post(Message::new(
+                "sender".to_string(),
+                "//nope".to_string()
+            )))
+        );
+
+        if let ChatResponse::Latest(log, _) =
+            chat.process(ChatCommand::GetLatest(UpdateId::default()))
+        {
+            assert_eq!(2, log.len());
+            assert_eq!(SYSTEM_SENDER, log[0].sender);
+            assert!(log[0].text.starts_with("sender rolled 2d6: "));
+            assert_eq!("/nope", log[1].text);
+        } else {
+            panic!("Failed to GetLatest");
+        }
+    }
+
+    #[test]
+    fn resending_a_post() {
+        let mut chat = ChatApp::new();
+
+        let mut message = Message::new("sender".to_string(), "test".to_string());
+        message.id = Some(42);
+
+        for _ in 0..2 {
+            assert_eq!(
+                ChatResponse::PostOk,
+                chat.process(ChatCommand::Post(message.clone()))
+            );
+        }
+
+        if let ChatResponse::Latest(log, _) =
+            chat.process(ChatCommand::GetLatest(UpdateId::default()))
+        {
+            assert_eq!(vec![message], log);
+        } else {
+            panic!("Failed to GetLatest");
+        }
+    }
+
+    #[test]
+    fn posting_if_latest() {
+        let mut chat = ChatApp::new();
+        let start = chat.update_id;
+        let next = UpdateId { seq: 1, ..start };
+
+        let first = Message::new("first".to_string(), "test".to_string());
+        assert_eq!(
+            ChatResponse::PostOk,
+            chat.process(ChatCommand::PostIfLatest(first.clone(), start))
+        );
+
+        // Someone else posted since the start, so the reply isn't posted.
+        let reply = Message::new("second".to_string(), "reply".to_string());
+        assert_eq!(
+            ChatResponse::Latest(vec![first], next),
+            chat.process(ChatCommand::PostIfLatest(reply.clone(), start))
+        );
+        assert_eq!(
+            ChatResponse::PostOk,
+            chat.process(ChatCommand::PostIfLatest(reply, next))
+        );
+        assert_eq!(2, chat.history().len());
+    }
+
+    #[test]
+    fn batches_are_atomic() {
+        let mut chat = ChatApp::new();
+        let start = chat.update_id;
+
+        let message = Message::new("sender".to_string(), "test".to_string());
+        assert_eq!(
+            ChatResponse::Batch(vec![
+                ChatResponse::PostOk,
+                ChatResponse::Latest(vec![message.clone()], UpdateId { seq: 1, ..start })
+            ]),
+            chat.process(ChatCommand::Batch(vec![
+                ChatCommand::Post(message.clone()),
+                ChatCommand::GetLatest(start),
+            ]))
+        );
+
+        // The post is rolled back when the vote fails.
+        let before = chat.clone();
+        assert_eq!(
+            ChatResponse::Error(ChatError::BatchFailed(
+                1,
+                Box::new(ChatError::UnknownPoll(0))
+            )),
+            chat.process(ChatCommand::Batch(vec![
+                ChatCommand::Post(message),
+                ChatCommand::Vote {
+                    poll: 0,
+                    option: 0,
+                    voter: "voter".to_string(),
+                    session: 1,
+                },
+            ]))
+        );
+        assert_eq!(before, chat);
+
+        assert_eq!(
+            ChatResponse::Error(ChatError::BatchFailed(0, Box::new(ChatError::NestedBatch))),
+            chat.process(ChatCommand::Batch(vec![ChatCommand::Batch(vec![])]))
+        );
+        assert_eq!(before, chat);
+
+        // A heartbeat is rolled back too, even when only a read fails after it.
+        assert_eq!(
+            ChatResponse::Error(ChatError::BatchFailed(
+                1,
+                Box::new(ChatError::UnknownAttachment(0))
+            )),
+            chat.process(ChatCommand::Batch(vec![
+                heartbeat("new", 1),
+                ChatCommand::GetChunk {
+                    attachment: 0,
+                    index: 0
+                },
+            ]))
+        );
+        assert_eq!(before, chat);
+        assert_eq!(None, chat.presence.session("new"));
+    }
+
+    #[test]
+    fn new_chats_have_new_epochs() {
+        let (a, b) = (ChatApp::new(), ChatApp::new());
+        assert_ne!(a.update_id.epoch, b.update_id.epoch);
+        assert!(b.update_id.is_newer_than(a.update_id));
+
+        let newer = UpdateId {
+            seq: 1,
+            ..a.update_id
+        };
+        assert!(newer.is_newer_than(a.update_id));
+        assert!(!a.update_id.is_newer_than(newer));
+    }
+
+    fn heartbeat(name: &str, session: u64) -> ChatCommand {
+        ChatCommand::Heartbeat {
+            name: name.to_string(),
+            session,
+        }
+    }
+
+    fn rename(from: &str, to: &str, session: u64) -> ChatCommand {
+        ChatCommand::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+            session,
+        }
+    }
+
+    #[test]
+    fn system_events() {
+        let mut chat = ChatApp::new();
+
+        assert_eq!(ChatResponse::HeartbeatOk, chat.process(heartbeat("a", 1)));
+        chat.process(heartbeat("a", 1));
+        chat.process(heartbeat("b", 2));
+        for name in &["", SYSTEM_SENDER, "a\nsystem: hi"] {
+            assert_eq!(
+                ChatResponse::Error(ChatError::InvalidName(name.to_string())),
+                chat.process(heartbeat(name, 3))
+            );
+        }
+
+        // Only the session connected with a name can use it.
+        assert_eq!(
+            ChatResponse::Error(ChatError::NameTaken("b".to_string())),
+            chat.process(heartbeat("b", 1))
+        );
+        assert_eq!(
+            ChatResponse::Error(ChatError::NotConnected("b".to_string())),
+            chat.process(rename("b", "d", 1))
+        );
+        assert_eq!(
+            ChatResponse::Error(ChatError::NameTaken("b".to_string())),
+            chat.process(rename("a", "b", 1))
+        );
+        assert_eq!(
+            ChatResponse::Renamed("c".to_string()),
+            chat.process(rename("a", "c", 1))
+        );
+
+        // Users can't post as the server.
+        let spoofed = Message {
+            kind: MessageKind::System,
+            ..Message::new("b".to_string(), "a went idle".to_string())
+        };
+        chat.process(ChatCommand::Post(spoofed));
+
+        let latest = chat.latest();
+        let latest: Vec<_> = latest.iter().map(|m| (m.kind, m.text.as_str())).collect();
+        assert_eq!(
+            vec![
+                (MessageKind::Notice, "a joined the chat"),
+                (MessageKind::Notice, "b joined the chat"),
+                (MessageKind::Notice, "a is now known as c"),
+                (MessageKind::User, "a went idle"),
+            ],
+            latest
+        );
+        assert_eq!(1, chat.history().len());
+    }
+
+    #[test]
+    fn notices_are_kept_apart() {
+        let mut chat = ChatApp::new();
+        for i in 0..MAX_CHAT_MESSAGES {
+            chat.process(ChatCommand::Post(Message::new(
+                "sender".to_string(),
+                format!("message {}", i),
+            )));
+        }
+        for i in 0..2 * MAX_NOTICES as u64 {
+            assert_eq!(
+                ChatResponse::Batch(vec![ChatResponse::HeartbeatOk]),
+                chat.process(ChatCommand::Batch(vec![heartbeat(
+                    &format!("user{}", i),
+                    i
+                )]))
+            );
+        }
+
+        assert_eq!(MAX_CHAT_MESSAGES, chat.history().len());
+        assert_eq!(MAX_CHAT_MESSAGES + MAX_NOTICES, chat.latest().len());
+        assert_eq!("user9 joined the chat", chat.latest().last().unwrap().text);
+    }
+
+    #[test]
+    fn ordering_by_the_server() {
+        let mut chat = ChatApp::new();
+        let post = |text: &str, sent_time| {
+            ChatCommand::Post(Message {
+                sent_time,
+                ..Message::new("sender".to_string(), text.to_string())
+            })
+        };
+
+        // The clients' clocks don't change where their posts go.
+        chat.process(post("future", Utc::now() + chrono::Duration::days(1)));
+        chat.process(heartbeat("a", 1));
+        chat.process(post("past", Utc::now() - chrono::Duration::days(1)));
+
+        let texts: Vec<_> = chat.latest().into_iter().map(|m| m.text).collect();
+        assert_eq!(vec!["future", "a joined the chat", "past"], texts);
+    }
 }
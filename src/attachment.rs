@@ -0,0 +1,265 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ChatError;
+
+/// The number of bytes sent in each [crate::ChatCommand::UploadChunk]. Small enough that a chunk
+/// and its command fit in one datagram.
+pub const CHUNK_SIZE: usize = 512;
+/// The largest file that can be uploaded.
+pub const MAX_ATTACHMENT_SIZE: usize = 1024 * 1024;
+/// The total size of the attachments kept by the server. The oldest attachments are dropped once
+/// this is exceeded.
+pub const MAX_STORED_BYTES: usize = 16 * MAX_ATTACHMENT_SIZE;
+/// The number of uploads that can be in progress at once. Starting another upload abandons the
+/// oldest one.
+pub const MAX_PENDING_UPLOADS: usize = 8;
+/// The longest file name that an attachment can have.
+pub const MAX_NAME_LENGTH: usize = 64;
+
+/// Describes a stored file so that it can be shown in the history and downloaded.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct AttachmentInfo {
+    pub id: usize,
+    pub name: String,
+    pub size: usize,
+    /// The CRC-32 of the file's contents.
+    pub checksum: u32,
+}
+
+impl AttachmentInfo {
+    /// The number of chunks the file is split into.
+    pub fn chunk_count(&self) -> usize {
+        chunk_count(self.size)
+    }
+}
+
+/// Get the number of chunks needed to send a file of the given size.
+pub fn chunk_count(size: usize) -> usize {
+    (size + CHUNK_SIZE - 1) / CHUNK_SIZE
+}
+
+/// Get the checksum used to verify that a file was transferred correctly.
+pub fn checksum(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+/// A file that is still being uploaded.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+struct Upload {
+    sender: String,
+    info: AttachmentInfo,
+    /// Shared for the same reason as the stored files.
+    chunks: Vec<Option<Arc<Vec<u8>>>>,
+}
+
+/// The uploaded files and the uploads that are still in progress.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+pub struct Attachments {
+    uploads: BTreeMap<usize, Upload>,
+    /// Shared so that keeping a copy to roll a [crate::ChatCommand::Batch] back to doesn't
+    /// copy every file.
+    stored: BTreeMap<usize, (AttachmentInfo, Arc<Vec<u8>>)>,
+    stored_bytes: usize,
+    next_id: usize,
+}
+
+impl Attachments {
+    /// Start a new upload and return its id. The id is also the id of the attachment once the
+    /// upload is finished.
+    pub fn start(
+        &mut self,
+        sender: String,
+        name: String,
+        size: usize,
+        checksum: u32,
+    ) -> Result<usize, ChatError> {
+        if size > MAX_ATTACHMENT_SIZE {
+            return Err(ChatError::AttachmentTooLarge(size));
+        }
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+            return Err(ChatError::InvalidAttachmentName);
+        }
+
+        // Make room by abandoning the oldest upload.
+        if self.uploads.len() >= MAX_PENDING_UPLOADS {
+            let oldest = *self.uploads.keys().next().unwrap();
+            self.uploads.remove(&oldest);
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.uploads.insert(
+            id,
+            Upload {
+                sender,
+                info: AttachmentInfo {
+                    id,
+                    name,
+                    size,
+                    checksum,
+                },
+                chunks: vec![None; chunk_count(size)],
+            },
+        );
+
+        Ok(id)
+    }
+
+    /// Store one chunk of an upload. Receiving the same chunk twice is fine.
+    pub fn add_chunk(&mut self, id: usize, index: usize, data: Vec<u8>) -> Result<(), ChatError> {
+        let upload = self
+            .uploads
+            .get_mut(&id)
+            .ok_or(ChatError::UnknownUpload(id))?;
+
+        // Every chunk but the last one is full.
+        if index >= upload.chunks.len()
+            || data.len() != CHUNK_SIZE.min(upload.info.size - CHUNK_SIZE * index)
+        {
+            return Err(ChatError::InvalidChunk(index));
+        }
+
+        upload.chunks[index] = Some(Arc::new(data));
+
+        Ok(())
+    }
+
+    /// Reassemble an upload, verify its checksum and store it. Returns who uploaded the file and
+    /// the description of the new attachment.
+    pub fn finish(&mut self, id: usize) -> Result<(String, AttachmentInfo), ChatError> {
+        let upload = self.uploads.get(&id).ok_or(ChatError::UnknownUpload(id))?;
+
+        if let Some(missing) = upload.chunks.iter().position(Option::is_none) {
+            return Err(ChatError::MissingChunk(missing));
+        }
+
+        let upload = self.uploads.remove(&id).unwrap();
+        let mut data = Vec::with_capacity(upload.info.size);
+        for chunk in upload.chunks.iter().flatten() {
+            data.extend_from_slice(chunk);
+        }
+        if checksum(&data) != upload.info.checksum {
+            return Err(ChatError::ChecksumMismatch(id));
+        }
+
+        // Drop the oldest attachments until the new one fits.
+        self.stored_bytes += data.len();
+        while self.stored_bytes > MAX_STORED_BYTES {
+            let oldest = *self.stored.keys().next().unwrap();
+            let (info, _) = self.stored.remove(&oldest).unwrap();
+            self.stored_bytes -= info.size;
+        }
+
+        self.stored
+            .insert(id, (upload.info.clone(), Arc::new(data)));
+
+        Ok((upload.sender, upload.info))
+    }
+
+    /// Get one chunk of a stored attachment.
+    pub fn chunk(&self, id: usize, index: usize) -> Result<(AttachmentInfo, Vec<u8>), ChatError> {
+        let (info, data) = self
+            .stored
+            .get(&id)
+            .ok_or(ChatError::UnknownAttachment(id))?;
+
+        // An empty file is still sent as one empty chunk.
+        if index >= info.chunk_count().max(1) {
+            return Err(ChatError::InvalidChunk(index));
+        }
+
+        let start = index * CHUNK_SIZE;
+        let end = data.len().min(start + CHUNK_SIZE);
+        Ok((info.clone(), data[start..end].to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(attachments: &mut Attachments, data: &[u8]) -> Result<usize, ChatError> {
+        let id = attachments.start(
+            "sender".to_string(),
+            "file.txt".to_string(),
+            data.len(),
+            checksum(data),
+        )?;
+
+        for (i, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+            attachments.add_chunk(id, i, chunk.to_vec())?;
+        }
+
+        attachments.finish(id).map(|(_, info)| info.id)
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut attachments = Attachments::default();
+        let data: Vec<u8> = (0..(CHUNK_SIZE * 2 + 10)).map(|x| x as u8).collect();
+
+        let id = upload(&mut attachments, &data).unwrap();
+
+        let downloaded: Vec<u8> = (0..chunk_count(data.len()))
+            .flat_map(|i| attachments.chunk(id, i).unwrap().1)
+            .collect();
+        assert_eq!(data, downloaded);
+        assert_eq!(
+            Err(ChatError::InvalidChunk(3)),
+            attachments.chunk(id, 3).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn missing_chunk() {
+        let mut attachments = Attachments::default();
+        let data = vec![1; CHUNK_SIZE * 2];
+
+        let id = attachments
+            .start(
+                "sender".to_string(),
+                "file".to_string(),
+                data.len(),
+                checksum(&data),
+            )
+            .unwrap();
+        attachments.add_chunk(id, 1, vec![1; CHUNK_SIZE]).unwrap();
+
+        assert_eq!(Err(ChatError::MissingChunk(0)), attachments.finish(id));
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut attachments = Attachments::default();
+
+        let id = attachments
+            .start(
+                "sender".to_string(),
+                "file".to_string(),
+                3,
+                checksum(&[1, 2, 3]),
+            )
+            .unwrap();
+        attachments.add_chunk(id, 0, vec![3, 2, 1]).unwrap();
+
+        assert_eq!(Err(ChatError::ChecksumMismatch(id)), attachments.finish(id));
+    }
+
+    #[test]
+    fn too_large() {
+        let mut attachments = Attachments::default();
+
+        assert_eq!(
+            Err(ChatError::AttachmentTooLarge(MAX_ATTACHMENT_SIZE + 1)),
+            attachments.start(
+                "sender".to_string(),
+                "file".to_string(),
+                MAX_ATTACHMENT_SIZE + 1,
+                0
+            )
+        );
+    }
+}
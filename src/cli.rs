@@ -0,0 +1,215 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs,
+    net::{IpAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ds_libs::address::Address;
+
+use crate::context::{codec, Codec, Ctx, Faults, Key, Transport, FEATURES};
+
+/// Command line arguments split into positional arguments and `--name` or `--name=value` flags.
+/// Flags can go anywhere on the line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Args {
+    pub positional: Vec<String>,
+    flags: BTreeMap<String, Option<String>>,
+}
+
+impl Args {
+    pub fn parse<I>(args: I) -> Args
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut positional = vec![];
+        let mut flags = BTreeMap::new();
+        for arg in args {
+            match arg.strip_prefix("--") {
+                Some(flag) => {
+                    let mut split = flag.splitn(2, '=');
+                    let name = split.next().unwrap_or("").to_string();
+                    flags.insert(name, split.next().map(str::to_string));
+                }
+                None => positional.push(arg),
+            }
+        }
+
+        Args { positional, flags }
+    }
+
+    /// Take a flag that has no value, like `--verbose`.
+    pub fn flag(&mut self, name: &str) -> Result<bool> {
+        match self.flags.remove(name) {
+            Some(None) => Ok(true),
+            Some(Some(_)) => bail!("--{} doesn't take a value", name),
+            None => Ok(false),
+        }
+    }
+
+    /// Take a flag with a value, like `--codec=json`, and parse the value.
+    pub fn value<T>(&mut self, name: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        match self.flags.remove(name) {
+            Some(Some(value)) => value
+                .parse()
+                .map(Some)
+                .map_err(|e| anyhow!("Invalid value for --{}: {}", name, e)),
+            Some(None) => bail!("--{} needs a value, ex: --{}=<value>", name, name),
+            None => Ok(None),
+        }
+    }
+
+    /// Fail if any flags were given that weren't taken.
+    pub fn finish(&self) -> Result<()> {
+        match self.flags.keys().next() {
+            Some(name) => bail!("Unknown flag --{}", name),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The flags that change how a node talks over the network, shared by the client and server.
+pub struct NetworkOptions {
+    /// `--log-dropped`, log every datagram that is dropped.
+    pub log_dropped: bool,
+    /// `--codec=<name>`, what events are sent with.
+    pub codec: &'static dyn Codec,
+    /// Not `--no-compression`, offer to compress large events in handshakes.
+    pub compress: bool,
+    /// `--key-file=<path>`, the pre-shared key to authenticate every datagram with.
+    pub key: Option<Key>,
+    /// `--transport=udp|tcp|unix:<directory>`, how datagrams get to the other end.
+    pub transport: Transport,
+    /// `--drop=<chance>`, `--delay=<ms>[-<ms>]`, `--duplicate=<chance>` and `--reorder=<chance>`,
+    /// what goes wrong with the datagrams that are sent, for testing.
+    pub faults: Faults,
+}
+
+impl NetworkOptions {
+    /// The usage of the flags, to add to the usage of a binary.
+    pub const USAGE: &'static str = "[--log-dropped] [--codec=bincode|json|msgpack] \
+        [--no-compression] [--key-file=<path>] [--transport=udp|tcp|unix:<directory>] \
+        [--drop=<chance>] [--delay=<ms>[-<ms>]] [--duplicate=<chance>] [--reorder=<chance>]";
+
+    pub fn from_args(args: &mut Args) -> Result<NetworkOptions> {
+        let codec = match args.value::<String>("codec")? {
+            Some(name) => codec::by_name(&name).map_err(|e| anyhow!(e))?,
+            None => &codec::Bincode,
+        };
+
+        Ok(NetworkOptions {
+            log_dropped: args.flag("log-dropped")?,
+            codec,
+            compress: !args.flag("no-compression")?,
+            key: args
+                .value::<PathBuf>("key-file")?
+                .map(read_key)
+                .transpose()?,
+            transport: args.value("transport")?.unwrap_or_default(),
+            faults: Faults {
+                drop: chance(args, "drop")?,
+                delay: args.value("delay")?.unwrap_or_default(),
+                duplicate: chance(args, "duplicate")?,
+                reorder: chance(args, "reorder")?,
+            },
+        })
+    }
+
+    pub fn apply(&self, ctx: &mut Ctx<'_>) {
+        ctx.dropped().set_log(self.log_dropped);
+        ctx.set_codec(self.codec);
+        ctx.set_features(if self.compress { FEATURES } else { 0 });
+        if let Some(key) = &self.key {
+            ctx.set_key(key.clone());
+        }
+        if !self.faults.is_none() {
+            ctx.set_faults(self.faults.clone());
+        }
+    }
+}
+
+/// Take a flag with a chance from 0 to 1, like `--drop=0.1`, which is 0 without the flag.
+fn chance(args: &mut Args, name: &str) -> Result<f64> {
+    let chance = args.value(name)?.unwrap_or(0.0);
+    if !(0.0..=1.0).contains(&chance) {
+        bail!("--{} must be a chance from 0 to 1", name);
+    }
+    Ok(chance)
+}
+
+/// Resolve an address and port, like `[::1]:8080`, `127.0.0.1:8080` or `localhost:8080`. A
+/// host name is resolved to its first address, of either family. Addresses are always IPv6, so
+/// IPv4 addresses are stored IPv4-mapped, like `[::ffff:127.0.0.1]:8080`.
+pub fn parse_address<Node>(s: &str) -> Result<Address<Node>> {
+    let address = s
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("{} doesn't resolve to any address", s))?;
+    let ip = match address.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+
+    Ok(Address::new((ip, address.port())))
+}
+
+/// Read a key that is written as text, like the keys from `chat-server keygen`.
+pub fn read_key_file<T>(path: &Path) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the key file {:?}", path))?;
+    contents.parse().map_err(|e| anyhow!("{} in {:?}", e, path))
+}
+
+/// Read a key from the file. Whitespace around it is ignored, so the file can end in a newline.
+fn read_key(path: PathBuf) -> Result<Key> {
+    let contents =
+        fs::read(&path).with_context(|| format!("Failed to read the key file {:?}", path))?;
+    let start = contents.iter().position(|b| !b.is_ascii_whitespace());
+    let end = contents.iter().rposition(|b| !b.is_ascii_whitespace());
+    let key = match (start, end) {
+        (Some(start), Some(end)) => &contents[start..=end],
+        _ => &[][..],
+    };
+
+    Key::new(key).map_err(|e| anyhow!("{} in {:?}", e, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Args {
+        Args::parse(line.split(' ').map(str::to_string))
+    }
+
+    #[test]
+    fn parsing_flags() {
+        let mut args = args("server --log-dropped [::1]:8080 --interval=5 data");
+
+        assert_eq!(vec!["server", "[::1]:8080", "data"], args.positional);
+        assert!(args.flag("log-dropped").unwrap());
+        assert!(!args.flag("log-dropped").unwrap());
+        assert_eq!(Some(5), args.value::<u32>("interval").unwrap());
+        assert!(args.finish().is_ok());
+    }
+
+    #[test]
+    fn invalid_flags() {
+        assert!(args("a --interval=five").value::<u32>("interval").is_err());
+        assert!(args("a --interval").value::<u32>("interval").is_err());
+        assert!(args("a --verbose=yes").flag("verbose").is_err());
+        assert!(args("a --unknown").finish().is_err());
+        assert!(chance(&mut args("a --drop=1.5"), "drop").is_err());
+    }
+}
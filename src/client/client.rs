@@ -1,50 +1,80 @@
-use std::{env, net::ToSocketAddrs, time::Duration, time::SystemTime};
+use std::{env, path::PathBuf, time::Duration};
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use chat_application::{
-    context::{self, Ctx},
-    ChatCommand, ChatResponse, Message,
+    cli::{parse_address, read_key_file, Args, NetworkOptions},
+    context::{self, Ctx, PublicKey},
+    is_valid_name,
+    persistence::Outbox,
+    ChatCommand, ChatError, ChatResponse, UpdateId,
 };
+use commands::Action;
 use crossterm::event::{EventStream, KeyCode, KeyModifiers};
-use ds_libs::{address::Address, Context, HandleMessage, HandleTimer, InitializeNode};
+use ds_libs::{Context, HandleMessage, HandleTimer, InitializeNode};
 use futures::{select, FutureExt, Stream, StreamExt};
 use interface::Interface;
 use simple_server::user::Client;
 use tokio::time::sleep;
+use transfer::Transfer;
 
+mod commands;
 mod interface;
+mod transfer;
 
-fn parse_address<Node>(s: &str) -> Result<Address<Node>> {
-    for ip_port in s.to_socket_addrs()? {
-        if let std::net::SocketAddr::V6(ip_port) = ip_port {
-            return Ok(Address::new((*ip_port.ip(), ip_port.port())))
-        }
-    }
-
-    Err(anyhow!(
-        "Failed to produce an IPv6:port pair from the provided address: {}",
-        s
-    ))
+/// The flags of `chat-client`.
+fn client_options(args: &mut Args) -> Result<(NetworkOptions, Option<PublicKey>, Option<PathBuf>)> {
+    let options = NetworkOptions::from_args(args)?;
+    let server_key = args
+        .value::<PathBuf>("server-key")?
+        .map(|path| read_key_file(&path))
+        .transpose()?;
+    let outbox = args.value::<PathBuf>("outbox")?;
+    args.finish()?;
+    Ok((options, server_key, outbox))
 }
 
 #[tokio::main]
 async fn main() {
-    let args: Vec<_> = env::args().collect();
+    let mut args = Args::parse(env::args().skip(1));
+    let (options, server_key, outbox_path) = match client_options(&mut args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
 
-    if args.len() != 4 {
-        eprintln!("You must provide only 3 arguments: <your-name> <local IPv6 address and port. Ex: [::1]:8081> <server IPv6 address and port. Ex: [::1]:8080>");
+    let args = args.positional;
+    if args.len() != 3 {
+        eprintln!("You must provide only 3 arguments: <your-name> <local address and port. Ex: [::1]:8081> <server address and port. Ex: [::1]:8080 or localhost:8080> {} [--server-key=<path>] [--outbox=<path>]", NetworkOptions::USAGE);
         return;
     }
 
-    let name = args[1].clone();
-    let local_address = match parse_address(&args[2]) {
+    let mut name = args[0].clone();
+    if !is_valid_name(&name) {
+        eprintln!("{}", ChatError::InvalidName(name));
+        return;
+    }
+    // Ties the name to this client, so nobody else can take it while it's connected.
+    let session = rand::random();
+    // The posts that weren't answered before the client last closed are sent again first.
+    let outbox_path =
+        outbox_path.unwrap_or_else(|| PathBuf::from(format!(".chat-outbox-{}.json", name)));
+    let mut outbox = match Outbox::open(&outbox_path) {
+        Ok(outbox) => outbox,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", outbox_path.display(), e);
+            return;
+        }
+    };
+    let local_address = match parse_address(&args[1]) {
         Ok(a) => a,
         Err(e) => {
             eprintln!("Failed to get the local address: {:?}", e);
             return;
         }
     };
-    let server_address = match parse_address(&args[3]) {
+    let server_address = match parse_address(&args[2]) {
         Ok(a) => a,
         Err(e) => {
             eprintln!("Failed to get the server address: {:?}", e);
@@ -52,10 +82,23 @@ async fn main() {
         }
     };
 
-    let mut interface = Interface::new();
+    let mut ctx = match Ctx::with_transport(local_address.id(), options.transport.clone()).await {
+        Ok(ctx) => ctx,
+        Err(e) => {
+            eprintln!("Failed to bind the port: {}", e);
+            std::process::exit(1);
+        }
+    };
+    options.apply(&mut ctx);
+    if let Some(key) = server_key {
+        ctx.set_server_key(key);
+    }
+    ctx.hello(&server_address);
 
     let mut node = Client::new(server_address, None);
-    let mut ctx = Ctx::new(local_address.id()).await;
+
+    // After binding, so a failure isn't printed over the interface.
+    let mut interface = Interface::new();
 
     let mut terminal_events = key_events().fuse();
     let mut client_events = ctx.event_stream().boxed().fuse();
@@ -64,13 +107,18 @@ async fn main() {
 
     node.init(&mut ctx);
 
-    let mut latest_id = 0;
+    let mut latest_id = UpdateId::default();
+    let mut transfer: Option<Transfer> = None;
+    // The id of the post in the outbox that the command in flight sends.
+    let mut sending: Option<u64> = None;
 
     loop {
         select! {
             event = terminal_events.select_next_some() => {
                 match event {
                     crossterm::event::Event::Resize(..) => interface.render(),
+                    // Any key closes the popup without doing anything else.
+                    crossterm::event::Event::Key(_) if interface.close_popup() => {},
                     crossterm::event::Event::Key(key) => {
                         match key.code {
                             KeyCode::Esc => {
@@ -82,8 +130,56 @@ async fn main() {
                             KeyCode::Enter => {
                                 if node.command.is_none() {
                                     let text = interface.clear_input();
-                                    node.command = Some(ChatCommand::Post(Message{sender:name.clone(), text, sent_time: SystemTime::now().into()}));
-                                    node.send_command(&mut ctx);
+                                    let started = match commands::parse(&name, session, &text) {
+                                        // Saved before it's sent, so it isn't lost if the client
+                                        // closes before the server answers.
+                                        Ok(Action::Send(ChatCommand::Post(post))) => {
+                                            match outbox.push(post) {
+                                                Ok(()) => {
+                                                    interface.set_status(String::new());
+                                                    if let Some((command, id)) = pending_post(&outbox, latest_id) {
+                                                        node.command = Some(command);
+                                                        node.send_command(&mut ctx);
+                                                        sending = Some(id);
+                                                    }
+                                                },
+                                                Err(e) => interface.set_status(format!("Failed to save the post: {}", e)),
+                                            }
+                                            None
+                                        },
+                                        Ok(Action::Send(command)) => {
+                                            interface.set_status(String::new());
+                                            // Fetch the history with the command so it shows up
+                                            // without waiting for the next poll.
+                                            node.command = Some(ChatCommand::Batch(vec![
+                                                command,
+                                                ChatCommand::GetLatest(latest_id),
+                                            ]));
+                                            node.send_command(&mut ctx);
+                                            None
+                                        },
+                                        Ok(Action::Upload { path, text }) => {
+                                            Some(Transfer::upload(name.clone(), path, text))
+                                        },
+                                        Ok(Action::Download { attachment, path }) => {
+                                            Some(Ok(Transfer::download(attachment, path)))
+                                        },
+                                        Err(usage) => {
+                                            interface.set_status(usage);
+                                            None
+                                        },
+                                    };
+
+                                    match started {
+                                        Some(Ok(t)) => {
+                                            interface.set_status(t.progress());
+                                            node.command = Some(t.command());
+                                            node.send_command(&mut ctx);
+                                            transfer = Some(t);
+                                        },
+                                        Some(Err(e)) => interface.set_status(e),
+                                        None => {},
+                                    }
                                 }
                             },
                             KeyCode::Char('c') if key.modifiers == KeyModifiers::CONTROL => {
@@ -106,13 +202,36 @@ async fn main() {
 
                         // Check if the client got a response.
                         match node.response.take() {
-                            Some(ChatResponse::Latest(history, id)) if id > latest_id => {
+                            // While a transfer is running every command in flight is part of it.
+                            Some(res) if transfer.is_some() => {
                                 node.command = None;
-                                interface.set_history(history);
-                                latest_id = id;
+
+                                let t = transfer.as_mut().unwrap();
+                                match t.advance(res) {
+                                    Ok(false) => {
+                                        interface.set_status(t.progress());
+                                        node.command = Some(t.command());
+                                        node.send_command(&mut ctx);
+                                    },
+                                    Ok(true) => {
+                                        interface.set_status("Transfer complete".to_string());
+                                        transfer = None;
+                                    },
+                                    Err(e) => {
+                                        interface.set_status(e);
+                                        transfer = None;
+                                    },
+                                }
                             },
-                            Some(_) => {
+                            Some(res) => {
                                 node.command = None;
+                                // Any answer means the server got the post, even an error.
+                                if let Some(id) = sending.take() {
+                                    if let Err(e) = outbox.confirm(id) {
+                                        interface.set_status(format!("Failed to save the outbox: {}", e));
+                                    }
+                                }
+                                show_response(&mut interface, &mut latest_id, &mut name, res);
                             },
                             _ => {},
                         }
@@ -120,15 +239,32 @@ async fn main() {
                     context::Event::Request(_) => {
                         // Clients don't handle Requests.
                     },
+                    context::Event::Handshake(handshake) => {
+                        interface.set_status(handshake.to_string());
+                    },
                     context::Event::ResendTimer(t) => {
                         node.handle_timer(&mut ctx, t);
                     },
                 }
             },
             _ = sleep(Duration::from_millis(500)).fuse() => {
-                // poll the server for the latest history.
+                // Send the posts that are still waiting, otherwise poll the server for the latest
+                // history.
+                if node.command.is_none() && transfer.is_none() {
+                    if let Some((command, id)) = pending_post(&outbox, latest_id) {
+                        node.command = Some(command);
+                        node.send_command(&mut ctx);
+                        sending = Some(id);
+                    }
+                }
                 if node.command.is_none() {
-                    node.command = Some(ChatCommand::GetLatest(latest_id));
+                    node.command = Some(ChatCommand::Batch(vec![
+                        ChatCommand::Heartbeat {
+                            name: name.clone(),
+                            session,
+                        },
+                        ChatCommand::GetLatest(latest_id),
+                    ]));
                     node.send_command(&mut ctx);
                 }
             }
@@ -138,6 +274,49 @@ async fn main() {
     interface.close();
 }
 
+/// The command that sends the oldest post in the outbox, with the history so it shows up without
+/// waiting for the next poll, and the id of the post.
+fn pending_post(outbox: &Outbox, latest_id: UpdateId) -> Option<(ChatCommand, u64)> {
+    let post = outbox.front()?.clone();
+    let id = post.id?;
+    let command = ChatCommand::Batch(vec![
+        ChatCommand::Post(post),
+        ChatCommand::GetLatest(latest_id),
+    ]);
+    Some((command, id))
+}
+
+/// Show the history or error in a response, including those inside of a batch.
+fn show_response(
+    interface: &mut Interface,
+    latest_id: &mut UpdateId,
+    name: &mut String,
+    response: ChatResponse,
+) {
+    match response {
+        ChatResponse::Latest(history, id) if id.is_newer_than(*latest_id) => {
+            // The server restarted without its history, so the old history is gone.
+            if id.epoch != latest_id.epoch && *latest_id != UpdateId::default() {
+                interface.set_status("The server restarted".to_string());
+            }
+            interface.set_history(history);
+            *latest_id = id;
+        }
+        ChatResponse::Stats(stats) => interface.show_popup("Stats".to_string(), stats.to_string()),
+        ChatResponse::Renamed(new_name) => {
+            interface.set_status(format!("You are now known as {}", new_name));
+            *name = new_name;
+        }
+        ChatResponse::Error(e) => interface.set_status(e.to_string()),
+        ChatResponse::Batch(responses) => {
+            for response in responses {
+                show_response(interface, latest_id, name, response);
+            }
+        }
+        _ => {}
+    }
+}
+
 fn key_events() -> impl Stream<Item = crossterm::event::Event> {
     // get the reader
     EventStream::new().map(|e| e.unwrap())
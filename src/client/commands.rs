@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+
+use chat_application::{ChatCommand, Message};
+
+/// What the user asked for with a line of input.
+pub enum Action {
+    /// Send a single command to the server.
+    Send(ChatCommand),
+    /// Upload a file and post it with the given text.
+    Upload { path: PathBuf, text: String },
+    /// Download an attachment to the given path, or to its own name if no path is given.
+    Download {
+        attachment: usize,
+        path: Option<PathBuf>,
+    },
+}
+
+/// Turn a line typed by the user into an [Action]. Lines that start with one of the known slash
+/// commands are parsed into that command, everything else is posted as a message. The session is
+/// the one the client sends its heartbeats with.
+pub fn parse(name: &str, session: u64, input: &str) -> Result<Action, String> {
+    let (command, args) = match input.strip_prefix('/') {
+        Some(rest) => {
+            let mut split = rest.splitn(2, ' ');
+            (
+                split.next().unwrap_or(""),
+                split.next().unwrap_or("").trim(),
+            )
+        }
+        None => return Ok(Action::Send(post(name, input))),
+    };
+
+    match command {
+        // /poll <question> | <option> | <option> ...
+        "poll" => {
+            let mut parts = args.split('|').map(|p| p.trim().to_string());
+            let question = parts
+                .next()
+                .filter(|q| !q.is_empty())
+                .ok_or("Usage: /poll <question> | <option> | <option> ...".to_string())?;
+
+            Ok(Action::Send(ChatCommand::CreatePoll {
+                sender: name.to_string(),
+                question,
+                options: parts.filter(|o| !o.is_empty()).collect(),
+                session,
+            }))
+        }
+        // /vote <poll> <option>
+        "vote" => {
+            let mut parts = args.split_whitespace().map(str::parse::<usize>);
+            match (parts.next(), parts.next()) {
+                (Some(Ok(poll)), Some(Ok(option))) if option > 0 => {
+                    Ok(Action::Send(ChatCommand::Vote {
+                        poll,
+                        option: option - 1,
+                        voter: name.to_string(),
+                        session,
+                    }))
+                }
+                _ => Err("Usage: /vote <poll> <option>".to_string()),
+            }
+        }
+        // /close <poll>
+        "close" => match args.parse() {
+            Ok(poll) => Ok(Action::Send(ChatCommand::ClosePoll {
+                poll,
+                sender: name.to_string(),
+                session,
+            })),
+            Err(_) => Err("Usage: /close <poll>".to_string()),
+        },
+        // /upload <path> [message]
+        "upload" => {
+            let mut parts = args.splitn(2, ' ');
+            match parts.next() {
+                Some(path) if !path.is_empty() => Ok(Action::Upload {
+                    path: path.into(),
+                    text: parts.next().unwrap_or("").trim().to_string(),
+                }),
+                _ => Err("Usage: /upload <path> [message]".to_string()),
+            }
+        }
+        // /download <file> [path]
+        "download" => {
+            let mut parts = args.split_whitespace();
+            match parts.next().map(str::parse) {
+                Some(Ok(attachment)) => Ok(Action::Download {
+                    attachment,
+                    path: parts.next().map(PathBuf::from),
+                }),
+                _ => Err("Usage: /download <file> [path]".to_string()),
+            }
+        }
+        // /stats
+        "stats" => Ok(Action::Send(ChatCommand::Stats)),
+        // /nick <name>
+        "nick" if !args.is_empty() => Ok(Action::Send(ChatCommand::Rename {
+            from: name.to_string(),
+            to: args.to_string(),
+            session,
+        })),
+        "nick" => Err("Usage: /nick <name>".to_string()),
+        _ => Ok(Action::Send(post(name, input))),
+    }
+}
+
+fn post(name: &str, text: &str) -> ChatCommand {
+    // The id stays the same when the post is resent, even by the client after it restarts, so
+    // it is only posted once.
+    ChatCommand::Post(Message {
+        id: Some(rand::random()),
+        ..Message::new(name.to_string(), text.to_string())
+    })
+}
@@ -3,15 +3,19 @@ use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
 use std::io::{self, Stdout};
 use tui::{
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
-    widgets::{Block, Borders, List, Paragraph},
+    layout::{Constraint, Direction, Layout, Rect},
+    widgets::{Block, Borders, Clear, List, ListItem, Paragraph},
     Terminal,
 };
 
 pub struct Interface {
     terminal: Terminal<CrosstermBackend<Stdout>>,
-    history: Vec<Message>,
+    /// The history is only parsed and styled when it changes, not on every render.
+    history: Vec<ListItem<'static>>,
     input: String,
+    status: String,
+    /// The title and text of a popup shown over the chat, if there is one.
+    popup: Option<(String, String)>,
 }
 
 impl Interface {
@@ -24,6 +28,8 @@ impl Interface {
         let mut out = Interface {
             terminal: Terminal::new(backend).unwrap(),
             input: String::new(),
+            status: String::new(),
+            popup: None,
             history: vec![],
         };
 
@@ -34,10 +40,31 @@ impl Interface {
     }
 
     pub fn set_history(&mut self, history: Vec<Message>) {
-        self.history = history;
+        self.history = history.into_iter().map(Message::into).collect();
         self.render();
     }
 
+    /// Show a short message, like an error from the server, above the input box.
+    pub fn set_status(&mut self, status: String) {
+        self.status = status;
+        self.render();
+    }
+
+    /// Show the text in a popup over the chat until it is closed.
+    pub fn show_popup(&mut self, title: String, text: String) {
+        self.popup = Some((title, text));
+        self.render();
+    }
+
+    /// Close the popup. Returns false if there was no popup open.
+    pub fn close_popup(&mut self) -> bool {
+        let closed = self.popup.take().is_some();
+        if closed {
+            self.render();
+        }
+        closed
+    }
+
     pub fn clear_input(&mut self) -> String {
         let out = std::mem::take(&mut self.input);
         self.render();
@@ -61,7 +88,9 @@ impl Interface {
 
     pub fn render(&mut self) {
         let input_text = self.input.clone();
+        let status = self.status.clone();
         let history = self.history.clone();
+        let popup = self.popup.clone();
         self.terminal
             .draw(|f| {
                 // Split the screen in two.
@@ -70,15 +99,45 @@ impl Interface {
                     .constraints([Constraint::Percentage(80), Constraint::Percentage(20)])
                     .split(f.size());
 
-                let chat_history =
-                    List::new(history.into_iter().map(Message::into).collect::<Vec<_>>())
-                        .block(Block::default().title("Chat History").borders(Borders::ALL));
+                let chat_history = List::new(history)
+                    .block(Block::default().title("Chat History").borders(Borders::ALL));
                 f.render_widget(chat_history, sections[0]);
 
-                let input =
-                    Paragraph::new(input_text + "_").block(Block::default().borders(Borders::ALL));
+                let input = Paragraph::new(input_text + "_")
+                    .block(Block::default().title(status).borders(Borders::ALL));
                 f.render_widget(input, sections[1]);
+
+                if let Some((title, text)) = popup {
+                    let area = centered(f.size(), 60, 60);
+                    f.render_widget(Clear, area);
+                    f.render_widget(
+                        Paragraph::new(text)
+                            .block(Block::default().title(title).borders(Borders::ALL)),
+                        area,
+                    );
+                }
             })
             .unwrap();
     }
 }
+
+/// A rectangle in the middle of the area, taking up the given percentages of it.
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage((100 - height) / 2),
+            Constraint::Percentage(height),
+            Constraint::Percentage((100 - height) / 2),
+        ])
+        .split(area);
+
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage((100 - width) / 2),
+            Constraint::Percentage(width),
+            Constraint::Percentage((100 - width) / 2),
+        ])
+        .split(rows[1])[1]
+}
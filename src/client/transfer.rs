@@ -0,0 +1,195 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chat_application::{
+    attachment::{self, AttachmentInfo, CHUNK_SIZE, MAX_ATTACHMENT_SIZE},
+    ChatCommand, ChatResponse,
+};
+
+/// A file being sent to or from the server. The client can only have one command in flight, so a
+/// transfer is a sequence of commands where each one is sent after the previous one succeeds.
+pub enum Transfer {
+    Upload {
+        sender: String,
+        name: String,
+        text: String,
+        data: Vec<u8>,
+        /// The id the server gave the upload, once it has been started.
+        upload: Option<usize>,
+        next_chunk: usize,
+    },
+    Download {
+        attachment: usize,
+        path: Option<PathBuf>,
+        data: Vec<u8>,
+        /// Learned from the first chunk.
+        info: Option<AttachmentInfo>,
+        next_chunk: usize,
+    },
+}
+
+impl Transfer {
+    /// Read the file to upload.
+    pub fn upload(sender: String, path: PathBuf, text: String) -> Result<Transfer, String> {
+        let name = path
+            .file_name()
+            .ok_or(format!("{} is not a file", path.display()))?
+            .to_string_lossy()
+            .into_owned();
+        let data = fs::read(&path).map_err(|e| format!("Failed to read {}: {}", name, e))?;
+
+        if data.len() > MAX_ATTACHMENT_SIZE {
+            return Err(format!(
+                "{} is too large, files can be at most {} bytes",
+                name, MAX_ATTACHMENT_SIZE
+            ));
+        }
+
+        Ok(Transfer::Upload {
+            sender,
+            name,
+            text,
+            data,
+            upload: None,
+            next_chunk: 0,
+        })
+    }
+
+    pub fn download(attachment: usize, path: Option<PathBuf>) -> Transfer {
+        Transfer::Download {
+            attachment,
+            path,
+            data: vec![],
+            info: None,
+            next_chunk: 0,
+        }
+    }
+
+    /// The next command to send to the server.
+    pub fn command(&self) -> ChatCommand {
+        match self {
+            Transfer::Upload {
+                sender,
+                name,
+                data,
+                upload: None,
+                ..
+            } => ChatCommand::StartUpload {
+                sender: sender.clone(),
+                name: name.clone(),
+                size: data.len(),
+                checksum: attachment::checksum(data),
+            },
+            Transfer::Upload {
+                text,
+                data,
+                upload: Some(upload),
+                next_chunk,
+                ..
+            } => match data.chunks(CHUNK_SIZE).nth(*next_chunk) {
+                Some(chunk) => ChatCommand::UploadChunk {
+                    upload: *upload,
+                    index: *next_chunk,
+                    data: chunk.to_vec(),
+                },
+                None => ChatCommand::FinishUpload {
+                    upload: *upload,
+                    text: text.clone(),
+                },
+            },
+            Transfer::Download {
+                attachment,
+                next_chunk,
+                ..
+            } => ChatCommand::GetChunk {
+                attachment: *attachment,
+                index: *next_chunk,
+            },
+        }
+    }
+
+    /// Handle the server's response to the last command. Returns true once the transfer is done.
+    pub fn advance(&mut self, response: ChatResponse) -> Result<bool, String> {
+        match (self, response) {
+            (_, ChatResponse::Error(e)) => Err(format!("Transfer failed: {}", e)),
+            (Transfer::Upload { upload, .. }, ChatResponse::UploadStarted(id)) => {
+                *upload = Some(id);
+                Ok(false)
+            }
+            (Transfer::Upload { next_chunk, .. }, ChatResponse::ChunkOk) => {
+                *next_chunk += 1;
+                Ok(false)
+            }
+            (Transfer::Upload { .. }, ChatResponse::PostOk) => Ok(true),
+            (
+                Transfer::Download {
+                    path,
+                    data,
+                    info,
+                    next_chunk,
+                    ..
+                },
+                ChatResponse::Chunk(chunk_info, chunk),
+            ) => {
+                data.extend(chunk);
+                *next_chunk += 1;
+
+                if *next_chunk < chunk_info.chunk_count() {
+                    *info = Some(chunk_info);
+                    return Ok(false);
+                }
+
+                if attachment::checksum(data) != chunk_info.checksum {
+                    return Err(format!("{} was corrupted", chunk_info.name));
+                }
+
+                // Only use the file name from the server, never a path it sent.
+                let path = match path.take() {
+                    Some(path) => path,
+                    None => Path::new(&chunk_info.name)
+                        .file_name()
+                        .map(PathBuf::from)
+                        .ok_or(format!("{} is not a valid file name", chunk_info.name))?,
+                };
+                fs::write(&path, data)
+                    .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+                Ok(true)
+            }
+            (_, response) => Err(format!("Unexpected response: {:?}", response)),
+        }
+    }
+
+    /// A short description of how far along the transfer is.
+    pub fn progress(&self) -> String {
+        match self {
+            Transfer::Upload {
+                name,
+                data,
+                next_chunk,
+                ..
+            } => format!(
+                "Uploading {}: {}/{} chunks",
+                name,
+                next_chunk,
+                attachment::chunk_count(data.len())
+            ),
+            Transfer::Download {
+                attachment,
+                info,
+                next_chunk,
+                ..
+            } => match info {
+                Some(info) => format!(
+                    "Downloading {}: {}/{} chunks",
+                    info.name,
+                    next_chunk,
+                    info.chunk_count()
+                ),
+                None => format!("Downloading file #{}", attachment),
+            },
+        }
+    }
+}
@@ -1,4 +1,14 @@
-use std::{sync::Arc, task::Poll};
+use std::{
+    collections::BTreeMap,
+    fmt, io, mem,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
 
 use ds_libs::{amo_application, ManageMessageType, ManageTimerType};
 
@@ -11,71 +21,652 @@ use futures::{
 use serde::{Deserialize, Serialize};
 use simple_server::user::{Client, ResendTimer};
 use tokio::{
-    net::{ToSocketAddrs, UdpSocket},
+    net::{lookup_host, ToSocketAddrs},
     runtime::Handle,
     time::sleep,
 };
 
-use crate::{ChatApp, ChatCommand, ChatResponse};
+use crate::{persistence::Synced, ChatApp, ChatCommand, ChatResponse};
+
+use self::{
+    auth::{AuthError, Replays},
+    encryption::{ChannelError, Channels, Mode, Received},
+    envelope::{flags, Header, Hello},
+    faults::Faulty,
+    fragment::Reassembler,
+    link::Link,
+};
+pub use self::{
+    auth::{Key, MIN_KEY_SIZE, REPLAY_WINDOW},
+    codec::Codec,
+    compress::COMPRESSION_THRESHOLD,
+    encryption::{generate_keys, PrivateKey, PublicKey, NOISE_PARAMS},
+    envelope::{features, Handshake, FEATURES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION},
+    faults::{Delay, Faults, REORDER_DELAY},
+    link::Transport,
+    memory::Network,
+};
+
+mod auth;
+pub mod codec;
+mod compress;
+mod encryption;
+mod envelope;
+mod faults;
+mod fragment;
+mod link;
+mod memory;
+mod tcp;
+mod udp;
+#[cfg(unix)]
+mod unix;
+
+/// The largest datagram that is sent or received over UDP, anything larger is dropped. Fits in
+/// the smallest MTU that IPv6 allows, so datagrams aren't fragmented by the network. Larger
+/// messages are split into fragments of this size.
+pub const MAX_DATAGRAM_SIZE: usize = 1232;
+/// The largest message, once it is put back together and decompressed. The same over every
+/// transport, so that larger datagrams don't let a peer make a node buffer or allocate more.
+pub const MAX_MESSAGE_SIZE: usize = fragment::MAX_FRAGMENTS * MAX_DATAGRAM_SIZE;
+/// How long to wait for the rest of the fragments of a message.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a hello is sent until it is answered.
+pub const HELLO_INTERVAL: Duration = Duration::from_secs(1);
+/// The most peers whose handshakes are kept. Anyone can say hello, so this is bounded.
+const MAX_PEERS: usize = 1024;
+
+/// Why a datagram was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// The datagram couldn't be deserialized.
+    Malformed,
+    /// The datagram was larger than the transport allows, or the message had too many fragments.
+    Oversized,
+    /// Some of the fragments of the message never arrived.
+    Incomplete,
+    /// The datagram is from a version of the protocol that isn't supported.
+    Incompatible,
+    /// Receiving failed.
+    ReceiveFailed,
+    /// Sending failed, or the event couldn't be serialized to send.
+    SendFailed,
+    /// The datagram failed authentication with the [Key] or decryption, wasn't encrypted when it
+    /// had to be, or was replayed.
+    Unauthenticated,
+}
+
+impl DropReason {
+    const ALL: [DropReason; 7] = [
+        DropReason::Malformed,
+        DropReason::Oversized,
+        DropReason::Incomplete,
+        DropReason::Incompatible,
+        DropReason::ReceiveFailed,
+        DropReason::SendFailed,
+        DropReason::Unauthenticated,
+    ];
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DropReason::Malformed => write!(f, "malformed"),
+            DropReason::Oversized => write!(f, "oversized"),
+            DropReason::Incomplete => write!(f, "incomplete"),
+            DropReason::Incompatible => write!(f, "incompatible"),
+            DropReason::ReceiveFailed => write!(f, "failed receive"),
+            DropReason::SendFailed => write!(f, "failed send"),
+            DropReason::Unauthenticated => write!(f, "unauthenticated"),
+        }
+    }
+}
+
+/// Counts the datagrams that were dropped instead of crashing the node, and optionally logs each
+/// of them with the address of the other end.
+#[derive(Debug, Default)]
+pub struct Dropped {
+    counts: [AtomicU64; 7],
+    log: AtomicBool,
+}
+
+impl Dropped {
+    /// Log every dropped datagram to stderr.
+    pub fn set_log(&self, log: bool) {
+        self.log.store(log, Ordering::Relaxed);
+    }
+
+    /// The number of datagrams dropped for the reason.
+    pub fn count(&self, reason: DropReason) -> u64 {
+        self.counts[reason as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        DropReason::ALL.iter().map(|&r| self.count(r)).sum()
+    }
+
+    fn record<P>(&self, reason: DropReason, peer: Option<P>, error: &dyn fmt::Display)
+    where
+        P: fmt::Debug,
+    {
+        self.counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+
+        if self.log.load(Ordering::Relaxed) {
+            match peer {
+                Some(peer) => {
+                    eprintln!("Dropped a {} datagram, peer {:?}: {}", reason, peer, error)
+                }
+                None => eprintln!("Dropped a {} datagram: {}", reason, error),
+            }
+        }
+    }
+}
+
+impl fmt::Display for Dropped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts: Vec<_> = DropReason::ALL
+            .iter()
+            .map(|&r| format!("{} {}", self.count(r), r))
+            .collect();
+        write!(f, "{}", counts.join(", "))
+    }
+}
 
 pub struct Ctx<'a> {
-    socket: Arc<UdpSocket>,
+    link: Arc<dyn Link>,
+    dropped: Arc<Dropped>,
+    session: Arc<Session>,
+    /// What events are sent with.
+    codec: &'static dyn Codec,
+    /// What every datagram is authenticated with, if anything.
+    key: Option<Key>,
+    channels: Arc<Channels>,
+    /// The id of the next message sent, so that its fragments can be told apart from others.
+    next_message_id: u64,
     timer_sink: UnboundedSender<BoxFuture<'a, ResendTimer>>,
     timer_stream: Option<TimerStream<'a, ResendTimer>>,
+    /// What every message waits for before it's sent, if anything.
+    synced: Option<Synced>,
+}
+
+/// What was agreed on with the other ends in the handshakes.
+#[derive(Debug)]
+struct Session {
+    /// The features offered in handshakes.
+    offered: AtomicU8,
+    /// What was agreed on with each peer, and when it was last used.
+    peers: Mutex<BTreeMap<SocketAddr, (Agreement, Instant)>>,
+    /// The peers that we sent hellos to, and whether they answered.
+    greeted: Mutex<BTreeMap<SocketAddr, bool>>,
+}
+
+/// The version and features that both ends of a handshake use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Agreement {
+    version: u8,
+    features: u8,
+}
+
+impl Default for Agreement {
+    fn default() -> Self {
+        Agreement {
+            version: PROTOCOL_VERSION,
+            features: 0,
+        }
+    }
+}
+
+impl Session {
+    /// What was agreed on with the peer, the newest version and no features if it never shook
+    /// hands.
+    fn agreement(&self, peer: SocketAddr) -> Agreement {
+        let mut peers = self.peers.lock().unwrap();
+        match peers.get_mut(&peer) {
+            Some((agreement, used)) => {
+                *used = Instant::now();
+                *agreement
+            }
+            None => Agreement::default(),
+        }
+    }
+
+    /// Keep what was agreed on with the peer. Past [MAX_PEERS] the least recently used peer is
+    /// forgotten, and goes back to the defaults until it shakes hands again.
+    fn agree(&self, peer: SocketAddr, agreement: Agreement) {
+        let mut peers = self.peers.lock().unwrap();
+        if !peers.contains_key(&peer) && peers.len() >= MAX_PEERS {
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, &(_, used))| used)
+                .map(|(&peer, _)| peer);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+        peers.insert(peer, (agreement, Instant::now()));
+    }
+
+    /// Expect an answer to a hello from the peer.
+    fn greet(&self, peer: SocketAddr) {
+        self.greeted.lock().unwrap().entry(peer).or_insert(false);
+    }
+
+    /// Whether the peer answered our hello.
+    fn is_greeted(&self, peer: SocketAddr) -> bool {
+        let greeted = self.greeted.lock().unwrap();
+        greeted.get(&peer).copied().unwrap_or(false)
+    }
+
+    /// Record an answer from the peer. Returns whether it's the first one, or none if the peer
+    /// was never greeted.
+    fn answered(&self, peer: SocketAddr) -> Option<bool> {
+        let mut greeted = self.greeted.lock().unwrap();
+        greeted
+            .get_mut(&peer)
+            .map(|answered| !mem::replace(answered, true))
+    }
+
+    /// Greet the peer again, like after it lost our channel.
+    fn reset(&self, peer: SocketAddr) {
+        if let Some(answered) = self.greeted.lock().unwrap().get_mut(&peer) {
+            *answered = false;
+        }
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            offered: AtomicU8::new(FEATURES),
+            peers: Mutex::default(),
+            greeted: Mutex::default(),
+        }
+    }
 }
 
 impl<'a> Ctx<'a> {
+    /// Bind to the address over UDP, which can be IPv4 or IPv6. Binding to `[::]` receives from
+    /// both where the system supports it. Exits the process if the address can't be bound.
     pub async fn new<A>(addr: A) -> Ctx<'a>
     where
         A: ToSocketAddrs,
     {
-        let socket = match UdpSocket::bind(addr).await {
-            Ok(s) => s,
+        match Ctx::with_transport(addr, Transport::Udp).await {
+            Ok(ctx) => ctx,
             Err(e) => {
-                eprintln!("Failed to bind the port: {:?}", e);
+                eprintln!("Failed to bind the port: {}", e);
                 std::process::exit(1);
             }
-        };
+        }
+    }
+
+    /// Bind to the address with the transport. Both ends have to use the same one.
+    pub async fn with_transport<A>(addr: A, transport: Transport) -> io::Result<Ctx<'a>>
+    where
+        A: ToSocketAddrs,
+    {
+        let link = transport.bind(resolve(addr).await?)?;
 
         let (sender, receiver) = unbounded();
 
         let timer_stream = Some(TimerStream::new(receiver));
 
-        Ctx {
-            socket: Arc::new(socket),
+        Ok(Ctx {
+            link: Arc::from(link),
+            dropped: Arc::default(),
+            session: Arc::default(),
+            codec: &codec::Bincode,
+            key: None,
+            channels: Arc::default(),
+            // Start somewhere random so that a restarted node doesn't reuse recent ids.
+            next_message_id: rand::random(),
             timer_sink: sender,
             timer_stream,
-        }
+            synced: None,
+        })
+    }
+
+    /// The counts of the datagrams that were dropped. These are shared, so they keep counting
+    /// after the context is handed to a node.
+    pub fn dropped(&self) -> Arc<Dropped> {
+        Arc::clone(&self.dropped)
+    }
+
+    /// Send events with the codec. Events are received in any of the codecs that are built in.
+    pub fn set_codec(&mut self, codec: &'static dyn Codec) {
+        self.codec = codec;
+    }
+
+    /// Authenticate every datagram with the key, and drop the ones that weren't sent with it. The
+    /// other end needs the same key.
+    pub fn set_key(&mut self, key: Key) {
+        self.key = Some(key);
+    }
+
+    /// Accept encrypted channels with the server's static key, and drop every datagram that isn't
+    /// encrypted.
+    pub fn set_private_key(&mut self, key: PrivateKey) {
+        self.channels = Arc::new(Channels::new(Mode::Respond(key)));
+    }
+
+    /// Only talk to the server with the static key over an encrypted channel, which is opened by
+    /// [Ctx::hello]. Every datagram that isn't encrypted is dropped.
+    pub fn set_server_key(&mut self, key: PublicKey) {
+        self.channels = Arc::new(Channels::new(Mode::Initiate(key)));
+    }
+
+    /// Only offer these [features] in handshakes, like `0` to never compress. Defaults to all of
+    /// the [FEATURES] of this build.
+    pub fn set_features(&mut self, features: u8) {
+        self.session
+            .offered
+            .store(features & FEATURES, Ordering::Relaxed);
+    }
+
+    /// Hold back every message until the changes recorded before it was sent have reached the
+    /// disk, so that a response never confirms a change that a crash could still lose. Messages
+    /// are dropped if syncing fails.
+    pub fn set_synced(&mut self, synced: Synced) {
+        self.synced = Some(synced);
+    }
+
+    /// Drop, delay, duplicate and reorder the datagrams that are sent from now on, like a bad
+    /// network would. Set it before [Ctx::event_stream] so replies get the faults too.
+    pub fn set_faults(&mut self, faults: Faults) {
+        self.link = Arc::new(Faulty::new(Arc::clone(&self.link), faults));
+    }
+
+    /// Start the handshake with the node at the address. The hello is sent again every
+    /// [HELLO_INTERVAL] until it's answered, and the answer arrives as an [Event::Handshake].
+    /// With a server key, the encrypted channel is opened first, and opened again whenever the
+    /// server loses it.
+    pub fn hello<Node>(&self, dst: &ds_libs::address::Address<Node>) {
+        let link = Arc::clone(&self.link);
+        let dropped = Arc::clone(&self.dropped);
+        let session = Arc::clone(&self.session);
+        let channels = Arc::clone(&self.channels);
+        let key = self.key.clone();
+        let address = dst.id();
+        let hello = Hello::ours(self.session.offered.load(Ordering::Relaxed)).encode();
+
+        Handle::current().spawn(async move {
+            let peer = match resolve(address.clone()).await {
+                Ok(peer) => peer,
+                Err(e) => {
+                    dropped.record(DropReason::SendFailed, Some(address), &e);
+                    return;
+                }
+            };
+            session.greet(peer);
+
+            loop {
+                let datagram = if channels.needs_handshake(peer) {
+                    channels.initiate(peer)
+                } else if !session.is_greeted(peer) {
+                    channels.encrypt(peer, hello.clone())
+                } else if channels.is_initiator() {
+                    sleep(HELLO_INTERVAL).await;
+                    continue;
+                } else {
+                    return;
+                };
+
+                match datagram {
+                    // Sealed every time, so the hellos that are sent again aren't replays.
+                    Ok(datagram) => {
+                        if let Err(e) = link.send_to(&seal(&key, datagram), peer).await {
+                            dropped.record(DropReason::SendFailed, Some(peer), &e);
+                        }
+                    }
+                    Err(e) => dropped.record(DropReason::SendFailed, Some(peer), &e),
+                }
+                sleep(HELLO_INTERVAL).await;
+            }
+        });
     }
 
     pub fn event_stream(&mut self) -> impl Stream<Item = Event> + 'a {
         stream::select(
             self.message_stream(),
-            self.timer_stream
-                .take()
-                .unwrap()
-                .map(Event::ResendTimer),
+            self.timer_stream.take().unwrap().map(Event::ResendTimer),
         )
     }
 
     fn message_stream(&self) -> impl Stream<Item = Event> {
-        stream::unfold(Arc::clone(&self.socket), |socket| {
-            async move {
-                let mut buf = [0; 1024];
+        let incoming = Incoming {
+            link: Arc::clone(&self.link),
+            dropped: Arc::clone(&self.dropped),
+            session: Arc::clone(&self.session),
+            key: self.key.clone(),
+            replays: Replays::default(),
+            channels: Arc::clone(&self.channels),
+            reassembler: Reassembler::new(REASSEMBLY_TIMEOUT),
+        };
+        stream::unfold(incoming, |mut incoming| async move {
+            let event = incoming.next().await;
+            Some((event, incoming))
+        })
+    }
+}
 
-                // Get the message
-                let (size, _) = socket
-                    .recv_from(&mut buf)
-                    .await
-                    .expect("Failed receiving a message");
+/// Receives datagrams and turns them back into events.
+struct Incoming {
+    link: Arc<dyn Link>,
+    dropped: Arc<Dropped>,
+    session: Arc<Session>,
+    key: Option<Key>,
+    replays: Replays,
+    channels: Arc<Channels>,
+    reassembler: Reassembler,
+}
 
-                // Parse the message
-                let event = bincode::deserialize(&buf[..size])
-                    .expect("Failed at deserializing the message");
+impl Incoming {
+    /// Wait for the next event. Anything that isn't a message is dropped, so a stray packet
+    /// can't stop the node.
+    async fn next(&mut self) -> Event {
+        loop {
+            let (datagram, peer) = match self.link.recv_from().await {
+                Ok(received) => received,
+                Err(e) => {
+                    self.dropped
+                        .record(DropReason::ReceiveFailed, None::<SocketAddr>, &e);
+                    continue;
+                }
+            };
 
-                Some((event, socket))
+            let max = self.link.max_datagram();
+            if datagram.len() > max {
+                self.dropped.record(
+                    DropReason::Oversized,
+                    Some(peer),
+                    &format!("larger than {} bytes", max),
+                );
+                continue;
             }
-        })
+
+            match self.receive(peer, &datagram).await {
+                Ok(Some(event)) => return event,
+                Ok(None) => {}
+                Err((reason, e)) => self.dropped.record(reason, Some(peer), &e),
+            }
+        }
+    }
+
+    /// Handle one datagram. Returns the event once a whole one has arrived.
+    async fn receive(
+        &mut self,
+        peer: SocketAddr,
+        datagram: &[u8],
+    ) -> Result<Option<Event>, (DropReason, String)> {
+        let malformed = |e: &dyn fmt::Display| (DropReason::Malformed, e.to_string());
+        let unauthenticated = |e: &dyn fmt::Display| (DropReason::Unauthenticated, e.to_string());
+
+        let (header, body) = Header::open(datagram).map_err(|e| malformed(&e))?;
+        let (header, body) = self
+            .authenticate(peer, header, body, datagram)
+            .map_err(|e| unauthenticated(&e))?;
+
+        if !header.is_encrypted() {
+            if self.channels.is_enabled() {
+                return Err(unauthenticated(&ChannelError::NotEncrypted));
+            }
+            return self.receive_plain(peer, header, body).await;
+        }
+        match self
+            .channels
+            .receive(peer, body)
+            .map_err(|e| unauthenticated(&e))?
+        {
+            Received::Datagram(inner) => {
+                let (header, body) = Header::open(&inner).map_err(|e| malformed(&e))?;
+                if header.is_encrypted() || header.is_authenticated() {
+                    return Err(malformed(&"nested envelope"));
+                }
+                self.receive_plain(peer, header, body).await
+            }
+            Received::Reply(reply) => self.send_raw(peer, reply).await.map(|_| None),
+            Received::Reset => {
+                self.session.reset(peer);
+                Ok(None)
+            }
+            Received::Nothing => Ok(None),
+        }
+    }
+
+    /// Handle a datagram that was decrypted, or that didn't need to be.
+    async fn receive_plain(
+        &mut self,
+        peer: SocketAddr,
+        header: Header,
+        body: &[u8],
+    ) -> Result<Option<Event>, (DropReason, String)> {
+        let malformed = |e: &dyn fmt::Display| (DropReason::Malformed, e.to_string());
+
+        if header.flags & flags::HELLO != 0 {
+            return self
+                .handshake(peer, Hello::decode(body).map_err(|e| malformed(&e))?)
+                .await;
+        }
+
+        if header.version < MIN_PROTOCOL_VERSION || header.version > PROTOCOL_VERSION {
+            self.dropped.record(
+                DropReason::Incompatible,
+                Some(peer),
+                &format!("protocol v{}", header.version),
+            );
+            // Tell the other end why nothing it sends is understood.
+            let reject = Hello::reply(header.version, header.version, 0, 0).encode();
+            return self.reply(peer, reject).await.map(|_| None);
+        }
+        let codec = codec::by_tag(header.codec()).ok_or_else(|| {
+            (
+                DropReason::Incompatible,
+                format!("codec {} isn't built in", header.codec()),
+            )
+        })?;
+
+        for peer in self.reassembler.expire() {
+            self.dropped.record(
+                DropReason::Incomplete,
+                Some(peer),
+                &"timed out waiting for fragments",
+            );
+        }
+
+        let message = match self
+            .reassembler
+            .add(peer, body)
+            .map_err(|e| malformed(&e))?
+        {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        let message = if header.is_compressed() {
+            compress::decompress(&message, MAX_MESSAGE_SIZE).map_err(|e| malformed(&e))?
+        } else {
+            message
+        };
+        codec.decode(&message).map(Some).map_err(|e| malformed(&e))
+    }
+
+    /// Send a datagram back to the peer, over the encrypted channel if there is one.
+    async fn reply(&self, peer: SocketAddr, datagram: Vec<u8>) -> Result<(), (DropReason, String)> {
+        let datagram = self
+            .channels
+            .encrypt(peer, datagram)
+            .map_err(|e| (DropReason::SendFailed, e.to_string()))?;
+        self.send_raw(peer, datagram).await
+    }
+
+    async fn send_raw(
+        &self,
+        peer: SocketAddr,
+        datagram: Vec<u8>,
+    ) -> Result<(), (DropReason, String)> {
+        let datagram = seal(&self.key, datagram);
+        match self.link.send_to(&datagram, peer).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err((DropReason::SendFailed, e.to_string())),
+        }
+    }
+
+    /// Check that the datagram was sent with our key, if we have one, and isn't a replay. Returns
+    /// the envelope and its body without the authentication.
+    fn authenticate<'d>(
+        &mut self,
+        peer: SocketAddr,
+        header: Header,
+        body: &'d [u8],
+        datagram: &'d [u8],
+    ) -> Result<(Header, &'d [u8]), AuthError> {
+        match (&self.key, header.is_authenticated()) {
+            (Some(key), true) => {
+                let (datagram, stamp) = key.open(datagram)?;
+                self.replays.check(peer, stamp)?;
+                Header::open(datagram).map_err(|_| AuthError::InvalidTag)
+            }
+            (Some(_), false) => Err(AuthError::Missing),
+            (None, true) => Err(AuthError::Unexpected),
+            (None, false) => Ok((header, body)),
+        }
+    }
+
+    async fn handshake(
+        &mut self,
+        peer: SocketAddr,
+        hello: Hello,
+    ) -> Result<Option<Event>, (DropReason, String)> {
+        let handshake = match hello {
+            Hello::Hello { min, max, features } => {
+                let reply = Hello::reply(
+                    min,
+                    max,
+                    features,
+                    self.session.offered.load(Ordering::Relaxed),
+                );
+                if let Hello::Accept { version, features } = reply {
+                    self.session.agree(peer, Agreement { version, features });
+                }
+                return self.reply(peer, reply.encode()).await.map(|_| None);
+            }
+            Hello::Accept { version, features } => Handshake::Accepted { version, features },
+            Hello::Reject { min, max } => Handshake::Rejected { min, max },
+        };
+
+        // Only the peers we greeted get a say, and only their first answer counts, the rest are
+        // for hellos that were sent again.
+        let first = self.session.answered(peer).ok_or_else(|| {
+            (
+                DropReason::Malformed,
+                "an answer to a hello that wasn't sent".to_string(),
+            )
+        })?;
+        if let Handshake::Accepted { version, features } = handshake {
+            self.session.agree(peer, Agreement { version, features });
+        }
+        if !first {
+            return Ok(None);
+        }
+        Ok(Some(Event::Handshake(handshake)))
     }
 }
 
@@ -84,6 +675,8 @@ pub enum Event {
     Request(amo_application::Request<ChatCommand, Client<ChatApp>>),
     Response(amo_application::Response<ChatResponse>),
     ResendTimer(simple_server::user::ResendTimer),
+    /// The other end answered our hello.
+    Handshake(Handshake),
 }
 
 impl<'a> ManageMessageType<amo_application::Request<ChatCommand, Client<ChatApp>>> for Ctx<'a> {
@@ -92,17 +685,7 @@ impl<'a> ManageMessageType<amo_application::Request<ChatCommand, Client<ChatApp>
         dst: ds_libs::address::Address<Node>,
         msg: amo_application::Request<ChatCommand, Client<ChatApp>>,
     ) {
-        let msg =
-            bincode::serialize(&Event::Request(msg)).expect("Failed to serialize the message");
-
-        let socket = Arc::clone(&self.socket);
-        let address = dst.id();
-        Handle::current().spawn(async move {
-            socket
-                .send_to(&msg, address)
-                .await
-                .expect("Failed to send message");
-        });
+        self.send(&Event::Request(msg), udp::canonical(dst.id().into()));
     }
 }
 
@@ -112,20 +695,115 @@ impl<'a> ManageMessageType<amo_application::Response<ChatResponse>> for Ctx<'a>
         dst: ds_libs::address::Address<Node>,
         msg: amo_application::Response<ChatResponse>,
     ) {
-        let msg =
-            bincode::serialize(&Event::Response(msg)).expect("Failed to serialize the message");
+        self.send(&Event::Response(msg), udp::canonical(dst.id().into()));
+    }
+}
 
-        let socket = Arc::clone(&self.socket);
-        let address = dst.id();
+impl<'a> Ctx<'a> {
+    /// Send the event in the background, compressed if the other end agreed to it and split into
+    /// as many datagrams as it needs. Failures are counted as dropped. Nodes are addressed by
+    /// their socket address, so there's nothing to resolve.
+    fn send(&mut self, event: &Event, peer: SocketAddr) {
+        let msg = match self.codec.encode(event) {
+            Ok(msg) => msg,
+            Err(e) => {
+                self.dropped.record(DropReason::SendFailed, Some(peer), &e);
+                return;
+            }
+        };
+        if msg.len() > MAX_MESSAGE_SIZE {
+            self.dropped.record(
+                DropReason::Oversized,
+                Some(peer),
+                &format!("{} bytes is too large to send", msg.len()),
+            );
+            return;
+        }
+
+        let id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        let codec = self.codec.tag();
+        let key = self.key.clone();
+        let mut overhead = envelope::HEADER_SIZE;
+        if key.is_some() {
+            overhead += auth::TRAILER_SIZE;
+        }
+        if self.channels.is_enabled() {
+            overhead += encryption::OVERHEAD;
+        }
+        let max = self.link.max_datagram() - overhead;
+
+        let link = Arc::clone(&self.link);
+        let dropped = Arc::clone(&self.dropped);
+        let session = Arc::clone(&self.session);
+        let channels = Arc::clone(&self.channels);
+        let synced = self.synced.as_ref().map(Synced::wait);
         Handle::current().spawn(async move {
-            socket
-                .send_to(&msg, address)
-                .await
-                .expect("Failed to send message");
+            if let Some(synced) = synced {
+                if let Err(e) = synced.await {
+                    dropped.record(DropReason::SendFailed, Some(peer), &e);
+                    return;
+                }
+            }
+
+            let agreement = session.agreement(peer);
+            let compressed = if agreement.features & features::LZ4 != 0 {
+                compress::compress(&msg)
+            } else {
+                None
+            };
+            let header = Header::event(agreement.version, codec, compressed.is_some());
+            let payload = compressed.as_deref().unwrap_or(&msg[..]);
+
+            let fragments = match fragment::split(id, payload, max) {
+                Some(fragments) => fragments,
+                None => {
+                    dropped.record(
+                        DropReason::Oversized,
+                        Some(peer),
+                        &format!("{} bytes is too large to send", payload.len()),
+                    );
+                    return;
+                }
+            };
+            for fragment in fragments {
+                let datagram = match channels.encrypt(peer, header.wrap(&fragment)) {
+                    Ok(datagram) => seal(&key, datagram),
+                    Err(e) => {
+                        dropped.record(DropReason::SendFailed, Some(peer), &e);
+                        return;
+                    }
+                };
+                if let Err(e) = link.send_to(&datagram, peer).await {
+                    dropped.record(DropReason::SendFailed, Some(peer), &e);
+                    return;
+                }
+            }
         });
     }
 }
 
+/// The first address that the address resolves to, with IPv4-mapped addresses as plain IPv4 so
+/// they match the addresses that datagrams are received from.
+async fn resolve<A>(address: A) -> io::Result<SocketAddr>
+where
+    A: ToSocketAddrs,
+{
+    lookup_host(address)
+        .await?
+        .next()
+        .map(udp::canonical)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))
+}
+
+/// Authenticate the datagram, if there is a key.
+fn seal(key: &Option<Key>, datagram: Vec<u8>) -> Vec<u8> {
+    match key {
+        Some(key) => key.seal(datagram),
+        None => datagram,
+    }
+}
+
 impl<'a> ManageTimerType<simple_server::user::ResendTimer> for Ctx<'a> {
     fn add<Node>(
         &mut self,
@@ -182,19 +860,38 @@ impl<'a, T> Stream for TimerStream<'a, T> {
 
 #[cfg(test)]
 mod tests {
-    use std::{net::Ipv6Addr, str::FromStr, time::Duration};
+    use std::{future::Future, net::Ipv6Addr, str::FromStr, time::Duration};
 
-    use ds_libs::{address::Address, amo_application::Response};
-    use tokio::time::timeout;
+    use ds_libs::{
+        address::Address, amo_application::Response, HandleMessage, HandleTimer, InitializeNode,
+    };
+    use futures::future::{self, Either};
+    use simple_server::user::Server;
+    use tokio::{net::UdpSocket, time::timeout};
 
     use super::*;
+    use crate::{
+        persistence::{tests::test_dir, Outbox},
+        Message, UpdateId, MAX_CHAT_MESSAGES,
+    };
+
+    /// A context on the network, which doesn't need a free port.
+    async fn in_memory(network: &Network, port: u16) -> Ctx<'static> {
+        Ctx::with_transport(("::1", port), Transport::Memory(network.clone()))
+            .await
+            .unwrap()
+    }
+
+    fn address<Node>(port: u16) -> Address<Node> {
+        Address::new((Ipv6Addr::from_str("::1").unwrap(), port))
+    }
 
     #[tokio::test]
     async fn sending_response_to_self() {
-        let _address1 = Address::<()>::new((Ipv6Addr::from_str("::1").unwrap(), 8080));
-        let mut ctx1 = Ctx::new(("::1", 8080)).await;
-        let address2 = Address::<()>::new((Ipv6Addr::from_str("::1").unwrap(), 8081));
-        let ctx2 = Ctx::new(("::1", 8081)).await;
+        let network = Network::new();
+        let mut ctx1 = in_memory(&network, 8080).await;
+        let address2 = address::<()>(8081);
+        let ctx2 = in_memory(&network, 8081).await;
 
         let res = Response {
             result: ChatResponse::PostOk,
@@ -213,8 +910,8 @@ mod tests {
 
     #[tokio::test]
     async fn setting_timer() {
-        let address1 = Address::<()>::new((Ipv6Addr::from_str("::1").unwrap(), 8080));
-        let mut ctx1 = Ctx::new(("::1", 0)).await;
+        let address1 = address::<()>(8080);
+        let mut ctx1 = in_memory(&Network::new(), 8080).await;
 
         ds_libs::ManageTimerType::add(
             &mut ctx1,
@@ -226,4 +923,593 @@ mod tests {
         let event = timeout(Duration::from_secs(1), Box::pin(ctx1.event_stream()).next()).await;
         assert_eq!(Ok(Some(Event::ResendTimer(ResendTimer(1)))), event);
     }
+
+    /// Wrap a small event the way that it is sent.
+    fn envelope(event: &Event) -> Vec<u8> {
+        let fragment = fragment::split(1, &bincode::serialize(event).unwrap(), MAX_DATAGRAM_SIZE)
+            .unwrap()
+            .remove(0);
+        Header::event(PROTOCOL_VERSION, codec::Bincode.tag(), false).wrap(&fragment)
+    }
+
+    #[tokio::test]
+    async fn dropping_stray_datagrams() {
+        let ctx = Ctx::new(("::1", 0)).await;
+        let address = ctx.link.local_addr().unwrap();
+        let dropped = ctx.dropped();
+
+        let res = Response {
+            result: ChatResponse::PostOk,
+            sequence_number: 1,
+        };
+        let sender = UdpSocket::bind(("::1", 0)).await.unwrap();
+        sender.send_to(b"garbage", address).await.unwrap();
+        sender
+            .send_to(&[0; MAX_DATAGRAM_SIZE + 1], address)
+            .await
+            .unwrap();
+        sender
+            .send_to(&envelope(&Event::Response(res.clone())), address)
+            .await
+            .unwrap();
+
+        // The bad datagrams are skipped over.
+        let event = timeout(
+            Duration::from_secs(5),
+            Box::pin(ctx.message_stream()).next(),
+        )
+        .await;
+        assert_eq!(Ok(Some(Event::Response(res))), event);
+        assert_eq!(1, dropped.count(DropReason::Malformed));
+        assert_eq!(1, dropped.count(DropReason::Oversized));
+        assert_eq!(2, dropped.total());
+    }
+
+    #[tokio::test]
+    async fn sending_a_large_response() {
+        let network = Network::new();
+        let mut ctx1 = in_memory(&network, 8080).await;
+        let ctx2 = in_memory(&network, 8081).await;
+        let address2 = address::<()>(8081);
+
+        // Far more than fits in one datagram.
+        let history = (0..MAX_CHAT_MESSAGES)
+            .map(|i| Message::new(format!("sender {}", i), "x".repeat(MAX_DATAGRAM_SIZE)))
+            .collect();
+        let res = Response {
+            result: ChatResponse::Latest(history, UpdateId::default()),
+            sequence_number: 1,
+        };
+
+        ds_libs::ManageMessageType::add(&mut ctx1, address2, res.clone());
+
+        let event = timeout(
+            Duration::from_secs(5),
+            Box::pin(ctx2.message_stream()).next(),
+        )
+        .await;
+        assert_eq!(Ok(Some(Event::Response(res))), event);
+        assert_eq!(0, ctx2.dropped().total());
+    }
+
+    #[tokio::test]
+    async fn sending_over_tcp() {
+        let mut ctx1 = Ctx::with_transport(("::1", 0), Transport::Tcp)
+            .await
+            .unwrap();
+        let mut ctx2 = Ctx::with_transport(("::1", 0), Transport::Tcp)
+            .await
+            .unwrap();
+        let address1 = Address::<()>::new((
+            Ipv6Addr::from_str("::1").unwrap(),
+            ctx1.link.local_addr().unwrap().port(),
+        ));
+        let address2 = Address::<()>::new((
+            Ipv6Addr::from_str("::1").unwrap(),
+            ctx2.link.local_addr().unwrap().port(),
+        ));
+
+        let history = (0..MAX_CHAT_MESSAGES)
+            .map(|i| Message::new(format!("sender {}", i), "x".repeat(MAX_DATAGRAM_SIZE)))
+            .collect();
+        let res = Response {
+            result: ChatResponse::Latest(history, UpdateId::default()),
+            sequence_number: 1,
+        };
+        ds_libs::ManageMessageType::add(&mut ctx1, address2, res.clone());
+        let event = timeout(
+            Duration::from_secs(5),
+            Box::pin(ctx2.message_stream()).next(),
+        )
+        .await;
+        assert_eq!(Ok(Some(Event::Response(res.clone()))), event);
+
+        // The answer comes back over the connection that ctx1 opened.
+        ds_libs::ManageMessageType::add(&mut ctx2, address1, res.clone());
+        let event = timeout(
+            Duration::from_secs(5),
+            Box::pin(ctx1.message_stream()).next(),
+        )
+        .await;
+        assert_eq!(Ok(Some(Event::Response(res))), event);
+    }
+
+    #[tokio::test]
+    async fn handshaking() {
+        let network = Network::new();
+        let ctx1 = in_memory(&network, 8080).await;
+        let ctx2 = in_memory(&network, 8081).await;
+        let address2 = address::<()>(8081);
+        tokio::spawn(ctx2.message_stream().for_each(|_| async {}));
+
+        ctx1.hello(&address2);
+
+        let event = timeout(
+            Duration::from_secs(5),
+            Box::pin(ctx1.message_stream()).next(),
+        )
+        .await;
+        assert_eq!(
+            Ok(Some(Event::Handshake(Handshake::Accepted {
+                version: PROTOCOL_VERSION,
+                features: FEATURES,
+            }))),
+            event
+        );
+
+        // Both ends keep what they agreed on for the other one.
+        let agreement = Agreement {
+            version: PROTOCOL_VERSION,
+            features: FEATURES,
+        };
+        let address1 = ctx1.link.local_addr().unwrap();
+        assert_eq!(
+            agreement,
+            ctx1.session.agreement(ctx2.link.local_addr().unwrap())
+        );
+        assert_eq!(agreement, ctx2.session.agreement(address1));
+    }
+
+    #[test]
+    fn limiting_agreements() {
+        let session = Session::default();
+        let agreement = Agreement {
+            version: PROTOCOL_VERSION,
+            features: FEATURES,
+        };
+        let peer = |port| SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port);
+        for port in 0..=MAX_PEERS as u16 {
+            session.agree(peer(port), agreement);
+        }
+
+        assert_eq!(MAX_PEERS, session.peers.lock().unwrap().len());
+        assert_eq!(agreement, session.agreement(peer(MAX_PEERS as u16)));
+    }
+
+    #[tokio::test]
+    async fn ignoring_answers_to_other_hellos() {
+        let network = Network::new();
+        let ctx1 = in_memory(&network, 8080).await;
+        let ctx2 = in_memory(&network, 8081).await;
+        let address1 = ctx1.link.local_addr().unwrap();
+        let address2 = ctx2.link.local_addr().unwrap();
+
+        let accept = Hello::Accept {
+            version: PROTOCOL_VERSION,
+            features: FEATURES,
+        };
+        ctx2.link.send_to(&accept.encode(), address1).await.unwrap();
+        let event = timeout(
+            Duration::from_millis(200),
+            Box::pin(ctx1.message_stream()).next(),
+        )
+        .await;
+        assert!(event.is_err());
+        assert_eq!(1, ctx1.dropped().count(DropReason::Malformed));
+        assert_eq!(Agreement::default(), ctx1.session.agreement(address2));
+    }
+
+    #[tokio::test]
+    async fn rejecting_other_versions() {
+        let ctx = Ctx::new(("::1", 0)).await;
+        let address = ctx.link.local_addr().unwrap();
+        tokio::spawn(ctx.message_stream().for_each(|_| async {}));
+
+        let peer = UdpSocket::bind(("::1", 0)).await.unwrap();
+        let datagram = Header {
+            version: PROTOCOL_VERSION + 1,
+            flags: 0,
+        }
+        .wrap(b"from the future");
+        peer.send_to(&datagram, address).await.unwrap();
+
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        let size = timeout(Duration::from_secs(5), peer.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let (_, body) = Header::open(&buf[..size]).unwrap();
+        assert_eq!(
+            Ok(Hello::Reject {
+                min: MIN_PROTOCOL_VERSION,
+                max: PROTOCOL_VERSION,
+            }),
+            Hello::decode(body)
+        );
+        assert_eq!(1, ctx.dropped().count(DropReason::Incompatible));
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn sending_json() {
+        let network = Network::new();
+        let mut ctx1 = in_memory(&network, 8080).await;
+        ctx1.set_codec(&codec::Json);
+        let ctx2 = in_memory(&network, 8081).await;
+        let address2 = address::<()>(8081);
+
+        let res = Response {
+            result: ChatResponse::PostOk,
+            sequence_number: 1,
+        };
+        ds_libs::ManageMessageType::add(&mut ctx1, address2, res.clone());
+
+        let event = timeout(
+            Duration::from_secs(5),
+            Box::pin(ctx2.message_stream()).next(),
+        )
+        .await;
+        assert_eq!(Ok(Some(Event::Response(res))), event);
+    }
+
+    /// Receive the fragments of one event on a plain socket.
+    async fn receive_event(socket: &UdpSocket) -> (Header, Vec<u8>) {
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        loop {
+            let (size, from) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let (header, body) = Header::open(&buf[..size]).unwrap();
+            if let Some(message) = reassembler.add(from, body).unwrap() {
+                return (header, message);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn compressing_for_peers_that_agree() {
+        let mut ctx = Ctx::new(("::1", 0)).await;
+        let address = ctx.link.local_addr().unwrap();
+        tokio::spawn(ctx.message_stream().for_each(|_| async {}));
+
+        let peer = UdpSocket::bind(("::1", 0)).await.unwrap();
+        let peer_address = || {
+            Address::<()>::new((
+                Ipv6Addr::from_str("::1").unwrap(),
+                peer.local_addr().unwrap().port(),
+            ))
+        };
+        let history = (0..MAX_CHAT_MESSAGES)
+            .map(|i| Message::new(format!("sender {}", i), "x".repeat(MAX_DATAGRAM_SIZE)))
+            .collect();
+        let res = Response {
+            result: ChatResponse::Latest(history, UpdateId::default()),
+            sequence_number: 1,
+        };
+
+        // Not compressed before the handshake.
+        ds_libs::ManageMessageType::add(&mut ctx, peer_address(), res.clone());
+        let (header, _) = receive_event(&peer).await;
+        assert!(!header.is_compressed());
+
+        peer.send_to(&Hello::ours(features::LZ4).encode(), address)
+            .await
+            .unwrap();
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        let size = timeout(Duration::from_secs(5), peer.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let (_, body) = Header::open(&buf[..size]).unwrap();
+        assert_eq!(
+            Ok(Hello::Accept {
+                version: PROTOCOL_VERSION,
+                features: features::LZ4,
+            }),
+            Hello::decode(body)
+        );
+
+        ds_libs::ManageMessageType::add(&mut ctx, peer_address(), res.clone());
+        let (header, message) = receive_event(&peer).await;
+        assert!(header.is_compressed());
+        let message = compress::decompress(&message, usize::MAX).unwrap();
+        assert_eq!(Ok(Event::Response(res)), codec::Bincode.decode(&message));
+    }
+
+    #[tokio::test]
+    async fn receiving_compressed_events() {
+        let ctx = Ctx::new(("::1", 0)).await;
+        let address = ctx.link.local_addr().unwrap();
+
+        let res = Response {
+            result: ChatResponse::Latest(
+                vec![Message::new("sender".to_string(), "x".repeat(1000)); 10],
+                UpdateId::default(),
+            ),
+            sequence_number: 1,
+        };
+        let message =
+            compress::compress(&bincode::serialize(&Event::Response(res.clone())).unwrap())
+                .unwrap();
+        let fragment = fragment::split(1, &message, MAX_DATAGRAM_SIZE)
+            .unwrap()
+            .remove(0);
+        let datagram = Header::event(PROTOCOL_VERSION, codec::Bincode.tag(), true).wrap(&fragment);
+
+        let sender = UdpSocket::bind(("::1", 0)).await.unwrap();
+        sender.send_to(&datagram, address).await.unwrap();
+
+        let event = timeout(
+            Duration::from_secs(5),
+            Box::pin(ctx.message_stream()).next(),
+        )
+        .await;
+        assert_eq!(Ok(Some(Event::Response(res))), event);
+    }
+
+    #[tokio::test]
+    async fn authenticating_datagrams() {
+        let key = Key::new(b"a key that is long enough").unwrap();
+        let mut ctx1 = Ctx::new(("::1", 0)).await;
+        ctx1.set_key(key.clone());
+        let mut ctx2 = Ctx::new(("::1", 0)).await;
+        ctx2.set_key(key.clone());
+        let address2 = ctx2.link.local_addr().unwrap();
+        let dropped = ctx2.dropped();
+
+        let res = Response {
+            result: ChatResponse::PostOk,
+            sequence_number: 1,
+        };
+        let sender = UdpSocket::bind(("::1", 0)).await.unwrap();
+        // Not authenticated.
+        sender
+            .send_to(&envelope(&Event::Response(res.clone())), address2)
+            .await
+            .unwrap();
+        // Authenticated with another key.
+        let other = Key::new(b"another key that is long enough").unwrap();
+        sender
+            .send_to(
+                &other.seal(envelope(&Event::Response(res.clone()))),
+                address2,
+            )
+            .await
+            .unwrap();
+        // Replayed.
+        let sealed = key.seal(envelope(&Event::Response(res.clone())));
+        sender.send_to(&sealed, address2).await.unwrap();
+        sender.send_to(&sealed, address2).await.unwrap();
+
+        let mut events = Box::pin(ctx2.message_stream());
+        let event = timeout(Duration::from_secs(5), events.next()).await;
+        assert_eq!(Ok(Some(Event::Response(res.clone()))), event);
+
+        let address2 = Address::<()>::new((Ipv6Addr::from_str("::1").unwrap(), address2.port()));
+        ds_libs::ManageMessageType::add(&mut ctx1, address2, res.clone());
+        let event = timeout(Duration::from_secs(5), events.next()).await;
+        assert_eq!(Ok(Some(Event::Response(res))), event);
+        assert_eq!(3, dropped.count(DropReason::Unauthenticated));
+    }
+
+    #[tokio::test]
+    async fn encrypting_channels() {
+        let (private, public) = generate_keys();
+        let mut server = Ctx::new(("::1", 0)).await;
+        server.set_private_key(private);
+        let server_address = server.link.local_addr().unwrap();
+        let mut client = Ctx::new(("::1", 0)).await;
+        client.set_server_key(public);
+
+        let mut server_events = Box::pin(server.message_stream());
+        let mut client_events = Box::pin(client.message_stream());
+
+        // Nothing gets through without a channel.
+        let sender = UdpSocket::bind(("::1", 0)).await.unwrap();
+        let res = Response {
+            result: ChatResponse::PostOk,
+            sequence_number: 1,
+        };
+        sender
+            .send_to(&envelope(&Event::Response(res.clone())), server_address)
+            .await
+            .unwrap();
+
+        client.hello(&Address::<()>::new((
+            Ipv6Addr::from_str("::1").unwrap(),
+            server_address.port(),
+        )));
+        let server_task = tokio::spawn(async move {
+            server_events.next().await;
+        });
+        let event = timeout(Duration::from_secs(5), client_events.next()).await;
+        assert_eq!(
+            Ok(Some(Event::Handshake(Handshake::Accepted {
+                version: PROTOCOL_VERSION,
+                features: FEATURES,
+            }))),
+            event
+        );
+        assert_eq!(1, server.dropped().count(DropReason::Unauthenticated));
+        server_task.abort();
+    }
+
+    /// Run the server on the context until the work is done.
+    async fn with_server<T>(mut ctx: Ctx<'static>, work: impl Future<Output = T>) -> T {
+        let server = async {
+            let port = ctx.link.local_addr().unwrap().port();
+            let mut events = Box::pin(ctx.event_stream());
+            let mut node = Server::new(ChatApp::new());
+            let mut ctx = ds_libs::Context::new(address(port), &mut ctx);
+            node.init(&mut ctx);
+            while let Some(event) = events.next().await {
+                if let Event::Request(req) = event {
+                    node.handle_message(&mut ctx, req);
+                }
+            }
+        };
+
+        match future::select(Box::pin(server), Box::pin(work)).await {
+            Either::Right((result, _)) => result,
+            Either::Left(_) => panic!("the server stopped"),
+        }
+    }
+
+    /// Run a client on the context until the server at port 8080 answers the command.
+    async fn run_client(mut ctx: Ctx<'static>, command: ChatCommand) -> ChatResponse {
+        let port = ctx.link.local_addr().unwrap().port();
+        let mut events = Box::pin(ctx.event_stream());
+        let mut node: Client<ChatApp> = Client::new(address(8080), None);
+        let mut ctx = ds_libs::Context::new(address(port), &mut ctx);
+        node.init(&mut ctx);
+
+        node.command = Some(command);
+        node.send_command(&mut ctx);
+        loop {
+            match events.next().await {
+                Some(Event::Response(res)) => {
+                    node.handle_message(&mut ctx, res);
+                    if let Some(res) = node.response.take() {
+                        return res;
+                    }
+                }
+                Some(Event::ResendTimer(timer)) => node.handle_timer(&mut ctx, timer),
+                _ => {}
+            }
+        }
+    }
+
+    /// Post a message from each of the clients at once, each named after its port.
+    async fn post_from_each(clients: Vec<Ctx<'static>>) -> Vec<ChatResponse> {
+        future::join_all(clients.into_iter().map(|ctx| {
+            let port = ctx.link.local_addr().unwrap().port();
+            let message = Message::new(format!("client {}", port), "hello".to_string());
+            run_client(ctx, ChatCommand::Post(message))
+        }))
+        .await
+    }
+
+    /// Get the history with the reader.
+    async fn history(reader: Ctx<'static>) -> Vec<Message> {
+        match run_client(reader, ChatCommand::GetLatest(UpdateId::default())).await {
+            ChatResponse::Latest(history, _) => history,
+            res => panic!("expected the history, got {:?}", res),
+        }
+    }
+
+    async fn clients(network: &Network, ports: std::ops::Range<u16>) -> Vec<Ctx<'static>> {
+        let mut clients = Vec::new();
+        for port in ports {
+            clients.push(in_memory(network, port).await);
+        }
+        clients
+    }
+
+    #[tokio::test]
+    async fn running_a_server_and_clients_in_one_process() {
+        let network = Network::new();
+        let server = in_memory(&network, 8080).await;
+        let dropped = server.dropped();
+        let first = clients(&network, 8081..8091).await;
+        let second = clients(&network, 8091..8101).await;
+        let reader = in_memory(&network, 9000).await;
+
+        let work = with_server(server, async {
+            let mut posted = post_from_each(first).await;
+            posted.extend(post_from_each(second).await);
+            (posted, history(reader).await)
+        });
+        let (posted, history) = timeout(Duration::from_secs(5), work).await.unwrap();
+        assert_eq!(20, posted.len());
+        assert!(posted.iter().all(|res| *res == ChatResponse::PostOk));
+
+        // Only the second round of posts is still in the history.
+        assert_eq!(MAX_CHAT_MESSAGES, history.len());
+        let mut senders: Vec<_> = history.into_iter().map(|m| m.sender).collect();
+        senders.sort();
+        let expected: Vec<_> = (8091..8101)
+            .map(|port| format!("client {}", port))
+            .collect();
+        assert_eq!(expected, senders);
+        assert_eq!(0, dropped.total());
+    }
+
+    #[tokio::test]
+    async fn retrying_over_a_faulty_network() {
+        let faults = Faults {
+            drop: 0.3,
+            delay: "0-20".parse().unwrap(),
+            duplicate: 0.3,
+            reorder: 0.3,
+        };
+        let network = Network::new();
+        let mut server = in_memory(&network, 8080).await;
+        server.set_faults(faults.clone());
+        let mut clients = clients(&network, 8081..8086).await;
+        for client in &mut clients {
+            client.set_faults(faults.clone());
+        }
+        let reader = in_memory(&network, 9000).await;
+
+        // The clients send again until they're answered, and the server only runs each command
+        // once, so every message is posted exactly once.
+        let work = with_server(server, async {
+            (post_from_each(clients).await, history(reader).await)
+        });
+        let (posted, history) = timeout(Duration::from_secs(60), work).await.unwrap();
+        assert!(posted.iter().all(|res| *res == ChatResponse::PostOk));
+        assert_eq!(5, history.len());
+    }
+
+    #[tokio::test]
+    async fn resending_posts_after_restarting() {
+        let network = Network::new();
+        let server = in_memory(&network, 8080).await;
+        let reader = in_memory(&network, 9000).await;
+        let dir = test_dir("resending-posts-after-restarting");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("outbox");
+
+        // The client saves the post and sends it, but is closed before it's answered.
+        let mut outbox = Outbox::open(&path).unwrap();
+        outbox
+            .push(Message {
+                id: Some(7),
+                ..Message::new("client".to_string(), "hello".to_string())
+            })
+            .unwrap();
+        let post = ChatCommand::Post(outbox.front().unwrap().clone());
+        let first = in_memory(&network, 8081).await;
+        assert!(timeout(Duration::from_millis(100), run_client(first, post))
+            .await
+            .is_err());
+        drop(outbox);
+
+        // After restarting, it sends the post again with the same id and the server, which got
+        // the first one, doesn't post it twice.
+        let mut outbox = Outbox::open(&path).unwrap();
+        let second = in_memory(&network, 8082).await;
+        let work = with_server(server, async {
+            let post = outbox.front().unwrap().clone();
+            let response = run_client(second, ChatCommand::Post(post)).await;
+            outbox.confirm(7).unwrap();
+            (response, history(reader).await)
+        });
+        let (response, history) = timeout(Duration::from_secs(5), work).await.unwrap();
+        assert_eq!(ChatResponse::PostOk, response);
+        assert_eq!(1, history.len());
+        assert_eq!(None, Outbox::open(&path).unwrap().front());
+    }
 }
@@ -0,0 +1,322 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryInto,
+    fmt, mem,
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use super::envelope::{flags, FLAGS_OFFSET};
+
+/// The shortest key that is accepted.
+pub const MIN_KEY_SIZE: usize = 16;
+/// How far the timestamp of a datagram can be from our clock, so both ends' clocks have to be
+/// at least this close.
+pub const REPLAY_WINDOW: Duration = Duration::from_secs(30);
+/// The most nonces that are remembered for one peer, its datagrams past this many in one
+/// [REPLAY_WINDOW] are dropped.
+const MAX_NONCES: usize = 1 << 12;
+/// The most peers whose nonces are remembered at once, datagrams from more peers than this in one
+/// [REPLAY_WINDOW] are dropped.
+const MAX_PEERS: usize = 1 << 10;
+
+const TAG_SIZE: usize = 32;
+/// The timestamp, the nonce and the tag that are added to the end of every datagram.
+pub(crate) const TRAILER_SIZE: usize = 8 + 8 + TAG_SIZE;
+
+/// A datagram that failed authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AuthError {
+    /// The datagram isn't authenticated, but we have a key.
+    Missing,
+    /// The datagram is authenticated, but we don't have a key.
+    Unexpected,
+    /// The tag doesn't match, it was sent with another key or changed on the way.
+    InvalidTag,
+    /// The timestamp is outside of the [REPLAY_WINDOW].
+    Expired,
+    /// A datagram with the same nonce was already received.
+    Replayed,
+    /// Too many datagrams were received in the [REPLAY_WINDOW] to remember them all.
+    TooMany,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "not authenticated"),
+            AuthError::Unexpected => write!(f, "authenticated, but no key is set"),
+            AuthError::InvalidTag => write!(f, "invalid authentication tag"),
+            AuthError::Expired => write!(f, "timestamp is too far from our clock"),
+            AuthError::Replayed => write!(f, "replayed"),
+            AuthError::TooMany => write!(f, "too many datagrams to check for replays"),
+        }
+    }
+}
+
+/// A key shared by the client and the server that every datagram is authenticated with.
+#[derive(Clone)]
+pub struct Key {
+    mac: Hmac<Sha256>,
+}
+
+impl Key {
+    pub fn new(secret: &[u8]) -> Result<Key, String> {
+        if secret.len() < MIN_KEY_SIZE {
+            return Err(format!(
+                "The key must be at least {} bytes long",
+                MIN_KEY_SIZE
+            ));
+        }
+
+        let mac = Hmac::new_from_slice(secret).map_err(|e| e.to_string())?;
+        Ok(Key { mac })
+    }
+
+    /// Mark the datagram as authenticated and add the timestamp, a random nonce and the tag.
+    pub(crate) fn seal(&self, mut datagram: Vec<u8>) -> Vec<u8> {
+        datagram[FLAGS_OFFSET] |= flags::AUTH;
+        datagram.extend_from_slice(&now().to_le_bytes());
+        datagram.extend_from_slice(&rand::random::<u64>().to_le_bytes());
+
+        let mut mac = self.mac.clone();
+        mac.update(&datagram);
+        datagram.extend_from_slice(&mac.finalize().into_bytes());
+        datagram
+    }
+
+    /// Check the tag of an authenticated datagram. Returns the datagram without the trailer,
+    /// and its stamp to check for replays.
+    pub(crate) fn open<'d>(&self, datagram: &'d [u8]) -> Result<(&'d [u8], Stamp), AuthError> {
+        if datagram.len() < TRAILER_SIZE {
+            return Err(AuthError::InvalidTag);
+        }
+        let (signed, tag) = datagram.split_at(datagram.len() - TAG_SIZE);
+
+        let mut mac = self.mac.clone();
+        mac.update(signed);
+        mac.verify(tag).map_err(|_| AuthError::InvalidTag)?;
+
+        let (datagram, stamp) = signed.split_at(signed.len() - 16);
+        let stamp = Stamp {
+            time: u64::from_le_bytes(stamp[..8].try_into().unwrap()),
+            nonce: u64::from_le_bytes(stamp[8..].try_into().unwrap()),
+        };
+        Ok((datagram, stamp))
+    }
+}
+
+/// When an authenticated datagram was sent, and the nonce that makes it unique.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Stamp {
+    /// Milliseconds since the unix epoch.
+    time: u64,
+    nonce: u64,
+}
+
+/// Remembers the nonces of the datagrams within the [REPLAY_WINDOW], so that each is only
+/// accepted once, whichever address it comes from. Older datagrams are rejected by their
+/// timestamp instead. Every peer can only fill its own share of the nonces, so one that sends too
+/// much doesn't get the datagrams of the others dropped.
+#[derive(Debug, Default)]
+pub(crate) struct Replays {
+    /// The nonces by timestamp, and the peer each one came from.
+    seen: BTreeMap<u64, BTreeMap<u64, SocketAddr>>,
+    /// The number of nonces remembered for each peer.
+    counts: BTreeMap<SocketAddr, usize>,
+}
+
+impl Replays {
+    pub(crate) fn check(&mut self, peer: SocketAddr, stamp: Stamp) -> Result<(), AuthError> {
+        self.check_at(peer, stamp, now())
+    }
+
+    fn check_at(&mut self, peer: SocketAddr, stamp: Stamp, now: u64) -> Result<(), AuthError> {
+        let window = REPLAY_WINDOW.as_millis() as u64;
+
+        // Nothing older than the window can be accepted anymore, so forget it.
+        let recent = self.seen.split_off(&now.saturating_sub(window));
+        for old in mem::replace(&mut self.seen, recent).values() {
+            for sender in old.values() {
+                let count = self.counts.get_mut(sender).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(sender);
+                }
+            }
+        }
+
+        if stamp.time.saturating_add(window) < now || stamp.time > now.saturating_add(window) {
+            return Err(AuthError::Expired);
+        }
+
+        // The address isn't authenticated, so a nonce counts as seen from any of them.
+        if self
+            .seen
+            .get(&stamp.time)
+            .map_or(false, |nonces| nonces.contains_key(&stamp.nonce))
+        {
+            return Err(AuthError::Replayed);
+        }
+        let count = self.counts.get(&peer).copied().unwrap_or(0);
+        if count >= MAX_NONCES || (count == 0 && self.counts.len() >= MAX_PEERS) {
+            return Err(AuthError::TooMany);
+        }
+
+        self.seen
+            .entry(stamp.time)
+            .or_default()
+            .insert(stamp.nonce, peer);
+        *self.counts.entry(peer).or_insert(0) += 1;
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::envelope::Header;
+
+    fn datagram() -> Vec<u8> {
+        Header {
+            version: 1,
+            flags: 0,
+        }
+        .wrap(b"body")
+    }
+
+    #[test]
+    fn sealing_datagrams() {
+        let key = Key::new(b"a key that is long enough").unwrap();
+        let sealed = key.seal(datagram());
+        assert_eq!(datagram().len() + TRAILER_SIZE, sealed.len());
+
+        let (opened, _) = key.open(&sealed).unwrap();
+        let (header, body) = Header::open(opened).unwrap();
+        assert_eq!(flags::AUTH, header.flags);
+        assert_eq!(b"body", body);
+
+        let other = Key::new(b"another key that is long enough").unwrap();
+        assert_eq!(Err(AuthError::InvalidTag), other.open(&sealed).map(|_| ()));
+        let mut changed = sealed.clone();
+        changed[7] ^= 1;
+        assert_eq!(Err(AuthError::InvalidTag), key.open(&changed).map(|_| ()));
+
+        assert!(Key::new(b"short").is_err());
+    }
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn rejecting_replays() {
+        let mut replays = Replays::default();
+        let now = 1_000_000;
+        let stamp = Stamp {
+            time: now,
+            nonce: 7,
+        };
+
+        assert_eq!(Ok(()), replays.check_at(peer(1), stamp, now));
+        assert_eq!(
+            Err(AuthError::Replayed),
+            replays.check_at(peer(1), stamp, now + 1)
+        );
+        assert_eq!(
+            Ok(()),
+            replays.check_at(peer(1), Stamp { nonce: 8, ..stamp }, now + 1)
+        );
+
+        let window = REPLAY_WINDOW.as_millis() as u64;
+        assert_eq!(
+            Err(AuthError::Expired),
+            replays.check_at(peer(1), stamp, now + window + 1)
+        );
+        assert_eq!(
+            Ok(()),
+            replays.check_at(
+                peer(1),
+                Stamp {
+                    nonce: 9,
+                    time: now + window + 1
+                },
+                now + window + 1
+            )
+        );
+        // The old nonces were forgotten.
+        assert_eq!(Some(&1), replays.counts.get(&peer(1)));
+    }
+
+    #[test]
+    fn rejecting_replays_from_other_addresses() {
+        let key = Key::new(b"a key that is long enough").unwrap();
+        let sealed = key.seal(datagram());
+        let mut replays = Replays::default();
+
+        let (_, stamp) = key.open(&sealed).unwrap();
+        assert_eq!(Ok(()), replays.check(peer(1), stamp));
+        let (_, stamp) = key.open(&sealed).unwrap();
+        assert_eq!(Err(AuthError::Replayed), replays.check(peer(2), stamp));
+    }
+
+    #[test]
+    fn limiting_nonces_per_peer() {
+        let mut replays = Replays::default();
+        let now = 1_000_000;
+        for nonce in 0..MAX_NONCES as u64 {
+            let stamp = Stamp { time: now, nonce };
+            assert_eq!(Ok(()), replays.check_at(peer(1), stamp, now));
+        }
+
+        let stamp = Stamp {
+            time: now,
+            nonce: u64::MAX,
+        };
+        assert_eq!(
+            Err(AuthError::TooMany),
+            replays.check_at(peer(1), stamp, now)
+        );
+        // The other peers are still heard.
+        assert_eq!(Ok(()), replays.check_at(peer(2), stamp, now));
+
+        // A nonce is only accepted once, even from another peer.
+        assert_eq!(
+            Err(AuthError::Replayed),
+            replays.check_at(peer(3), stamp, now)
+        );
+
+        for port in 3..MAX_PEERS as u16 + 1 {
+            let stamp = Stamp {
+                time: now,
+                nonce: u64::MAX - u64::from(port),
+            };
+            assert_eq!(Ok(()), replays.check_at(peer(port), stamp, now));
+        }
+        let new = peer(MAX_PEERS as u16 + 1);
+        let stamp = Stamp {
+            time: now,
+            nonce: u64::MAX / 2,
+        };
+        assert_eq!(Err(AuthError::TooMany), replays.check_at(new, stamp, now));
+
+        // Once the window has passed, the idle peers make room for new ones.
+        let later = now + REPLAY_WINDOW.as_millis() as u64 + 1;
+        let stamp = Stamp {
+            time: later,
+            nonce: 0,
+        };
+        assert_eq!(Ok(()), replays.check_at(new, stamp, later));
+        assert_eq!(1, replays.counts.len());
+    }
+}
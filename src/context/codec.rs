@@ -0,0 +1,135 @@
+use super::Event;
+
+/// Turns events into bytes and back. The codec of every message is tagged in its envelope, so
+/// nodes can read messages in any of the codecs that were built in, whichever one they send
+/// with.
+pub trait Codec: Send + Sync {
+    /// The tag in the envelope. Never reuse the tag of another codec.
+    fn tag(&self) -> u8;
+
+    /// The name the codec is picked with, like `--codec=json`.
+    fn name(&self) -> &'static str;
+
+    fn encode(&self, event: &Event) -> Result<Vec<u8>, String>;
+
+    fn decode(&self, bytes: &[u8]) -> Result<Event, String>;
+}
+
+/// The compact default.
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn tag(&self) -> u8 {
+        0
+    }
+
+    fn name(&self) -> &'static str {
+        "bincode"
+    }
+
+    fn encode(&self, event: &Event) -> Result<Vec<u8>, String> {
+        bincode::serialize(event).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Event, String> {
+        bincode::deserialize(bytes).map_err(|e| e.to_string())
+    }
+}
+
+/// Readable with standard tools, and easy to write clients for in other languages.
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn tag(&self) -> u8 {
+        1
+    }
+
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, event: &Event) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(event).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Event, String> {
+        serde_json::from_slice(bytes).map_err(|e| e.to_string())
+    }
+}
+
+/// A compact format that, unlike bincode, has libraries for most languages.
+#[cfg(feature = "msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn tag(&self) -> u8 {
+        2
+    }
+
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn encode(&self, event: &Event) -> Result<Vec<u8>, String> {
+        rmp_serde::to_vec(event).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Event, String> {
+        rmp_serde::from_read_ref(bytes).map_err(|e| e.to_string())
+    }
+}
+
+/// The codecs that were built in.
+static CODECS: &[&dyn Codec] = &[
+    &Bincode,
+    #[cfg(feature = "json")]
+    &Json,
+    #[cfg(feature = "msgpack")]
+    &MessagePack,
+];
+
+/// Find a built in codec by its name.
+pub fn by_name(name: &str) -> Result<&'static dyn Codec, String> {
+    CODECS
+        .iter()
+        .copied()
+        .find(|c| c.name() == name)
+        .ok_or_else(|| {
+            let names: Vec<_> = CODECS.iter().map(|c| c.name()).collect();
+            format!("{} is not a codec, use one of: {}", name, names.join(", "))
+        })
+}
+
+pub(crate) fn by_tag(tag: u8) -> Option<&'static dyn Codec> {
+    CODECS.iter().copied().find(|c| c.tag() == tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use ds_libs::amo_application::Response;
+
+    use super::*;
+    use crate::{ChatResponse, Message, UpdateId};
+
+    #[test]
+    fn round_trips() {
+        let event = Event::Response(Response {
+            result: ChatResponse::Latest(
+                vec![Message::new("sender".to_string(), "text".to_string())],
+                UpdateId::default(),
+            ),
+            sequence_number: 1,
+        });
+
+        for codec in CODECS {
+            let bytes = codec.encode(&event).unwrap();
+            assert_eq!(Ok(event.clone()), codec.decode(&bytes), "{}", codec.name());
+            assert_eq!(codec.name(), by_tag(codec.tag()).unwrap().name());
+        }
+
+        assert!(by_name("morse").is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::{convert::TryInto, fmt};
+
+/// Messages smaller than this are sent as is, they rarely get smaller.
+pub const COMPRESSION_THRESHOLD: usize = 512;
+
+/// The size of the message before it was compressed.
+const SIZE_PREFIX: usize = 4;
+
+/// A compressed message that can't be decompressed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CompressError {
+    /// The message is too short to have its size.
+    Truncated,
+    /// The message would be larger than the limit once decompressed.
+    TooLarge(usize),
+    Invalid(String),
+}
+
+impl fmt::Display for CompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressError::Truncated => write!(f, "too short for a compressed message"),
+            CompressError::TooLarge(size) => {
+                write!(f, "decompresses to {} bytes, more than allowed", size)
+            }
+            CompressError::Invalid(e) => write!(f, "invalid compressed message: {}", e),
+        }
+    }
+}
+
+/// Compress the message with LZ4. Returns nothing if it's below the [COMPRESSION_THRESHOLD] or
+/// doesn't get any smaller.
+pub(crate) fn compress(message: &[u8]) -> Option<Vec<u8>> {
+    if message.len() < COMPRESSION_THRESHOLD {
+        return None;
+    }
+
+    let mut compressed = (message.len() as u32).to_le_bytes().to_vec();
+    compressed.extend_from_slice(&lz4_flex::compress(message));
+    if compressed.len() >= message.len() {
+        return None;
+    }
+    Some(compressed)
+}
+
+/// Decompress a message, as long as it's no larger than `max` bytes once decompressed. The size
+/// is checked first, so a small datagram can't make us allocate a lot of memory.
+pub(crate) fn decompress(compressed: &[u8], max: usize) -> Result<Vec<u8>, CompressError> {
+    if compressed.len() < SIZE_PREFIX {
+        return Err(CompressError::Truncated);
+    }
+    let size = u32::from_le_bytes(compressed[..SIZE_PREFIX].try_into().unwrap()) as usize;
+    if size > max {
+        return Err(CompressError::TooLarge(size));
+    }
+
+    let message = lz4_flex::decompress(&compressed[SIZE_PREFIX..], size)
+        .map_err(|e| CompressError::Invalid(e.to_string()))?;
+    if message.len() != size {
+        return Err(CompressError::Invalid(format!(
+            "{} bytes instead of {}",
+            message.len(),
+            size
+        )));
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let message = "sender: the same text again. ".repeat(100).into_bytes();
+        let compressed = compress(&message).unwrap();
+        assert!(compressed.len() < message.len());
+        assert_eq!(Ok(message.clone()), decompress(&compressed, message.len()));
+
+        assert_eq!(None, compress(b"short"));
+        let random: Vec<u8> = (0..COMPRESSION_THRESHOLD).map(|_| rand::random()).collect();
+        assert_eq!(None, compress(&random));
+    }
+
+    #[test]
+    fn rejecting_bombs() {
+        let message = vec![0; 10_000];
+        let compressed = compress(&message).unwrap();
+        assert_eq!(
+            Err(CompressError::TooLarge(10_000)),
+            decompress(&compressed, 1000)
+        );
+        assert_eq!(Err(CompressError::Truncated), decompress(&[1], 1000));
+        assert!(decompress(&compressed[..10], 10_000).is_err());
+    }
+}
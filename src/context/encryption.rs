@@ -0,0 +1,716 @@
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use snow::{params::NoiseParams, Builder, HandshakeState, StatelessTransportState};
+
+use super::envelope::{flags, Header, HEADER_SIZE, PROTOCOL_VERSION};
+
+/// The client knows the static key of the server ahead of time, so the server is authenticated
+/// in a single round trip.
+pub const NOISE_PARAMS: &str = "Noise_NK_25519_ChaChaPoly_BLAKE2s";
+/// The size of the private and public keys.
+pub const KEY_SIZE: usize = 32;
+/// The most channels that are open at once. Past this a channel that was never used, or that has
+/// been idle for [CHANNEL_IDLE_TIMEOUT], is closed to make room.
+const MAX_CHANNELS: usize = 1024;
+/// How long a channel that was used has to be idle before a new one can take its place.
+const CHANNEL_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+/// How many handshakes are answered each second, on average. Each one costs a Diffie-Hellman,
+/// and anyone can start one.
+const HANDSHAKES_PER_SECOND: f64 = 100.0;
+/// How many handshakes can be answered at once, after a quiet period.
+const HANDSHAKE_BURST: f64 = 100.0;
+
+const TAG_SIZE: usize = 16;
+/// The envelope, the kind, the nonce and the tag around every encrypted datagram.
+pub(crate) const OVERHEAD: usize = HEADER_SIZE + 1 + 8 + TAG_SIZE;
+/// Large enough for either message of the handshake.
+const HANDSHAKE_SIZE: usize = 128;
+
+/// The kinds of encrypted datagrams, the first byte of their body.
+const INITIATION: u8 = 0;
+const RESPONSE: u8 = 1;
+const TRANSPORT: u8 = 2;
+/// Sent back when a transport datagram arrives for a channel that doesn't exist, so the other end
+/// starts over, such as after the server restarted.
+const UNKNOWN: u8 = 3;
+
+/// The private half of the server's static key. Keep it secret.
+#[derive(Clone)]
+pub struct PrivateKey([u8; KEY_SIZE]);
+
+/// The public half of the server's static key, pinned by the clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey([u8; KEY_SIZE]);
+
+/// Generate a new static key for a server.
+pub fn generate_keys() -> (PrivateKey, PublicKey) {
+    let keypair = Builder::new(params())
+        .generate_keypair()
+        .expect("Failed to generate keys");
+    let private = keypair.private.try_into().expect("Unexpected key size");
+    let public = keypair.public.try_into().expect("Unexpected key size");
+    (PrivateKey(private), PublicKey(public))
+}
+
+impl PrivateKey {
+    /// The key in hex, as it's written to the key file.
+    pub fn to_hex(&self) -> String {
+        to_hex(&self.0)
+    }
+}
+
+impl PublicKey {
+    pub fn to_hex(&self) -> String {
+        to_hex(&self.0)
+    }
+}
+
+impl FromStr for PrivateKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        from_hex(s).map(PrivateKey)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        from_hex(s).map(PublicKey)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parse a key from hex, ignoring whitespace around it.
+fn from_hex(s: &str) -> Result<[u8; KEY_SIZE], String> {
+    let s = s.trim();
+    let invalid = || format!("A key must be {} hex digits", KEY_SIZE * 2);
+    if s.len() != KEY_SIZE * 2 || !s.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut key = [0; KEY_SIZE];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+fn params() -> NoiseParams {
+    NOISE_PARAMS.parse().expect("Invalid noise parameters")
+}
+
+/// An encrypted datagram that can't be handled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ChannelError {
+    /// The datagram is encrypted, but encryption isn't set up on this end.
+    NotEnabled,
+    /// The datagram isn't encrypted, but this end requires it.
+    NotEncrypted,
+    /// There is no channel with the other end yet.
+    NoChannel,
+    /// A datagram with the same nonce was already received on the channel.
+    Replayed,
+    /// Too many handshakes were started lately, or every channel is in use.
+    Busy,
+    /// The handshake or the decryption failed.
+    Invalid(String),
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelError::NotEnabled => write!(f, "encrypted, but encryption isn't set up"),
+            ChannelError::NotEncrypted => write!(f, "not encrypted"),
+            ChannelError::NoChannel => write!(f, "no encrypted channel yet"),
+            ChannelError::Replayed => write!(f, "replayed"),
+            ChannelError::Busy => write!(f, "too many handshakes or channels"),
+            ChannelError::Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+fn invalid(e: snow::Error) -> ChannelError {
+    ChannelError::Invalid(e.to_string())
+}
+
+/// Which end of the handshakes this node is.
+pub(crate) enum Mode {
+    /// Answer handshakes with the static key, for the server.
+    Respond(PrivateKey),
+    /// Start handshakes with the node that has the key, for the client.
+    Initiate(PublicKey),
+}
+
+/// What to do with an encrypted datagram.
+pub(crate) enum Received {
+    /// The datagram that was inside, to handle like any other.
+    Datagram(Vec<u8>),
+    /// Send this back to the other end.
+    Reply(Vec<u8>),
+    /// The other end lost our channel, so the handshake has to start over.
+    Reset,
+    Nothing,
+}
+
+/// An open channel with a peer. Datagrams carry their nonce, so they can be lost or arrive out of
+/// order.
+struct Channel {
+    transport: StatelessTransportState,
+    next_nonce: u64,
+    window: Window,
+    last_used: Instant,
+    /// Whether the other end is known to have the keys, because it used the channel or because we
+    /// started it.
+    confirmed: bool,
+}
+
+impl Channel {
+    fn decrypt(&mut self, nonce: u64, body: &[u8]) -> Result<Vec<u8>, ChannelError> {
+        if !self.window.is_new(nonce) {
+            return Err(ChannelError::Replayed);
+        }
+
+        let mut datagram = vec![0; body.len()];
+        let size = self
+            .transport
+            .read_message(nonce, body, &mut datagram)
+            .map_err(invalid)?;
+        datagram.truncate(size);
+        // Only after it decrypted, so a forged nonce can't block a real one.
+        self.window.mark(nonce);
+        self.last_used = Instant::now();
+        self.confirmed = true;
+        Ok(datagram)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// The handshakes we started, waiting for a response.
+    pending: BTreeMap<SocketAddr, HandshakeState>,
+    open: BTreeMap<SocketAddr, Channel>,
+    /// The channel that was open before the peer's latest handshake, until the peer uses the new
+    /// one. The handshake may have been a duplicate or a late one that the peer never finished,
+    /// and it keeps using the old keys.
+    previous: BTreeMap<SocketAddr, Channel>,
+    handshakes: Bucket,
+}
+
+/// Limits how many handshakes are answered, refilled at [HANDSHAKES_PER_SECOND].
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Bucket {
+            tokens: HANDSHAKE_BURST,
+            refilled: Instant::now(),
+        }
+    }
+}
+
+impl Bucket {
+    fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .checked_duration_since(self.refilled)
+            .unwrap_or_default();
+        let refill = elapsed.as_secs_f64() * HANDSHAKES_PER_SECOND;
+        self.tokens = (self.tokens + refill).min(HANDSHAKE_BURST);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// The encrypted channels with each peer. When encryption isn't set up, datagrams go through
+/// unchanged.
+#[derive(Default)]
+pub(crate) struct Channels {
+    mode: Option<Mode>,
+    state: Mutex<State>,
+}
+
+impl Channels {
+    pub(crate) fn new(mode: Mode) -> Channels {
+        Channels {
+            mode: Some(mode),
+            state: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.mode.is_some()
+    }
+
+    pub(crate) fn is_initiator(&self) -> bool {
+        matches!(self.mode, Some(Mode::Initiate(_)))
+    }
+
+    /// Whether we have to start a handshake before anything can be sent to the peer.
+    pub(crate) fn needs_handshake(&self, peer: SocketAddr) -> bool {
+        self.is_initiator() && !self.state.lock().unwrap().open.contains_key(&peer)
+    }
+
+    /// Start a handshake with the peer, replacing any that was started before.
+    pub(crate) fn initiate(&self, peer: SocketAddr) -> Result<Vec<u8>, ChannelError> {
+        let server = match &self.mode {
+            Some(Mode::Initiate(server)) => server,
+            _ => return Err(ChannelError::NotEnabled),
+        };
+
+        let mut handshake = Builder::new(params())
+            .remote_public_key(&server.0)
+            .build_initiator()
+            .map_err(invalid)?;
+        let mut message = [0; HANDSHAKE_SIZE];
+        let size = handshake
+            .write_message(&[], &mut message)
+            .map_err(invalid)?;
+
+        self.state.lock().unwrap().pending.insert(peer, handshake);
+        Ok(wrap(INITIATION, &message[..size]))
+    }
+
+    /// Encrypt a datagram for the peer.
+    pub(crate) fn encrypt(
+        &self,
+        peer: SocketAddr,
+        datagram: Vec<u8>,
+    ) -> Result<Vec<u8>, ChannelError> {
+        if !self.is_enabled() {
+            return Ok(datagram);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        // Answer with the keys that the peer last used.
+        let channel = match (state.open.get_mut(&peer), state.previous.get_mut(&peer)) {
+            (Some(channel), Some(previous)) if !channel.confirmed && previous.confirmed => previous,
+            (Some(channel), _) => channel,
+            (None, _) => return Err(ChannelError::NoChannel),
+        };
+        let nonce = channel.next_nonce;
+        channel.next_nonce += 1;
+        channel.last_used = Instant::now();
+
+        let mut body = vec![0; 8 + datagram.len() + TAG_SIZE];
+        body[..8].copy_from_slice(&nonce.to_le_bytes());
+        let size = channel
+            .transport
+            .write_message(nonce, &datagram, &mut body[8..])
+            .map_err(invalid)?;
+        body.truncate(8 + size);
+        Ok(wrap(TRANSPORT, &body))
+    }
+
+    /// Handle the body of a datagram that was marked as encrypted.
+    pub(crate) fn receive(&self, peer: SocketAddr, body: &[u8]) -> Result<Received, ChannelError> {
+        let mode = self.mode.as_ref().ok_or(ChannelError::NotEnabled)?;
+        let (&kind, body) = body
+            .split_first()
+            .ok_or_else(|| ChannelError::Invalid("empty".to_string()))?;
+        let mut payload = [0; HANDSHAKE_SIZE];
+
+        match (kind, mode) {
+            (INITIATION, Mode::Respond(private)) => {
+                if !self.state.lock().unwrap().handshakes.take() {
+                    return Err(ChannelError::Busy);
+                }
+                let mut handshake = Builder::new(params())
+                    .local_private_key(&private.0)
+                    .build_responder()
+                    .map_err(invalid)?;
+                handshake
+                    .read_message(body, &mut payload)
+                    .map_err(invalid)?;
+                let mut message = [0; HANDSHAKE_SIZE];
+                let size = handshake
+                    .write_message(&[], &mut message)
+                    .map_err(invalid)?;
+
+                let transport = handshake.into_stateless_transport_mode().map_err(invalid)?;
+                self.open(peer, transport, false)?;
+                Ok(Received::Reply(wrap(RESPONSE, &message[..size])))
+            }
+            (RESPONSE, Mode::Initiate(_)) => {
+                let mut handshake = self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .pending
+                    .remove(&peer)
+                    .ok_or(ChannelError::NoChannel)?;
+                handshake
+                    .read_message(body, &mut payload)
+                    .map_err(invalid)?;
+
+                let transport = handshake.into_stateless_transport_mode().map_err(invalid)?;
+                self.open(peer, transport, true)?;
+                Ok(Received::Nothing)
+            }
+            (TRANSPORT, _) => self.decrypt(peer, body),
+            (UNKNOWN, Mode::Initiate(_)) => {
+                // Anyone can send this, but all it does is start the handshake over.
+                let mut state = self.state.lock().unwrap();
+                state.previous.remove(&peer);
+                match state.open.remove(&peer) {
+                    Some(_) => Ok(Received::Reset),
+                    None => Ok(Received::Nothing),
+                }
+            }
+            (kind, _) => Err(ChannelError::Invalid(format!(
+                "unexpected encrypted datagram of kind {}",
+                kind
+            ))),
+        }
+    }
+
+    fn decrypt(&self, peer: SocketAddr, body: &[u8]) -> Result<Received, ChannelError> {
+        if body.len() < 8 + TAG_SIZE {
+            return Err(ChannelError::Invalid("too short".to_string()));
+        }
+        let nonce = u64::from_le_bytes(body[..8].try_into().unwrap());
+        let body = &body[8..];
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let channel = match state.open.get_mut(&peer) {
+            Some(channel) => channel,
+            None if !self.is_initiator() => return Ok(Received::Reply(wrap(UNKNOWN, &[]))),
+            None => return Err(ChannelError::NoChannel),
+        };
+
+        let error = match channel.decrypt(nonce, body) {
+            Ok(datagram) => {
+                // The peer has the new keys, so it won't use the old ones again.
+                state.previous.remove(&peer);
+                return Ok(Received::Datagram(datagram));
+            }
+            Err(e) => e,
+        };
+        if let ChannelError::Invalid(_) = error {
+            if let Some(previous) = state.previous.get_mut(&peer) {
+                if let Ok(datagram) = previous.decrypt(nonce, body) {
+                    // The peer never finished the latest handshake, go back to the old channel.
+                    let previous = state.previous.remove(&peer).unwrap();
+                    state.open.insert(peer, previous);
+                    return Ok(Received::Datagram(datagram));
+                }
+            }
+            // The peer has keys that we don't, so it has to start over.
+            if !self.is_initiator() {
+                return Ok(Received::Reply(wrap(UNKNOWN, &[])));
+            }
+        }
+        Err(error)
+    }
+
+    /// Open a channel with the peer. The channel it had is kept as the previous one, unless that
+    /// would replace a channel that the peer used with one that it never did.
+    fn open(
+        &self,
+        peer: SocketAddr,
+        transport: StatelessTransportState,
+        confirmed: bool,
+    ) -> Result<(), ChannelError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.open.contains_key(&peer) && state.open.len() >= MAX_CHANNELS {
+            // Handshakes can come from spoofed addresses, so they never push out a channel that is
+            // in use.
+            let evicted = state
+                .open
+                .iter()
+                .filter(|(_, channel)| {
+                    !channel.confirmed || channel.last_used.elapsed() >= CHANNEL_IDLE_TIMEOUT
+                })
+                .min_by_key(|(_, channel)| (channel.confirmed, channel.last_used))
+                .map(|(&peer, _)| peer)
+                .ok_or(ChannelError::Busy)?;
+            state.open.remove(&evicted);
+            state.previous.remove(&evicted);
+        }
+
+        let channel = Channel {
+            transport,
+            next_nonce: 0,
+            window: Window::default(),
+            last_used: Instant::now(),
+            confirmed,
+        };
+        if let Some(old) = state.open.insert(peer, channel) {
+            let keep = state
+                .previous
+                .get(&peer)
+                .map_or(true, |previous| old.confirmed || !previous.confirmed);
+            if keep {
+                state.previous.insert(peer, old);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn wrap(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(1 + body.len());
+    datagram.push(kind);
+    datagram.extend_from_slice(body);
+
+    Header {
+        version: PROTOCOL_VERSION,
+        flags: flags::ENCRYPTED,
+    }
+    .wrap(&datagram)
+}
+
+/// The nonces received on a channel, so each is only accepted once. Nonces that are too far
+/// behind the newest one are rejected.
+#[derive(Debug, Default)]
+struct Window {
+    /// One past the newest nonce.
+    next: u64,
+    /// Bit `i` is set if the nonce `next - 1 - i` was received.
+    seen: u128,
+}
+
+impl Window {
+    fn is_new(&self, nonce: u64) -> bool {
+        if nonce >= self.next {
+            return true;
+        }
+        let age = self.next - 1 - nonce;
+        age < 128 && self.seen & (1 << age) == 0
+    }
+
+    fn mark(&mut self, nonce: u64) {
+        if nonce >= self.next {
+            let shift = nonce - self.next + 1;
+            self.seen = if shift >= 128 { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.next = nonce + 1;
+        } else {
+            self.seen |= 1 << (self.next - 1 - nonce);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port))
+    }
+
+    fn body(datagram: &[u8]) -> &[u8] {
+        let (header, body) = Header::open(datagram).unwrap();
+        assert_eq!(flags::ENCRYPTED, header.flags);
+        body
+    }
+
+    #[test]
+    fn opening_a_channel() {
+        let (private, public) = generate_keys();
+        let server = Channels::new(Mode::Respond(private));
+        let client = Channels::new(Mode::Initiate(public));
+        let (server_address, client_address) = (peer(8080), peer(8081));
+
+        assert!(client.needs_handshake(server_address));
+        assert_eq!(
+            Err(ChannelError::NoChannel),
+            client.encrypt(server_address, b"early".to_vec())
+        );
+
+        let initiation = client.initiate(server_address).unwrap();
+        let response = match server.receive(client_address, body(&initiation)) {
+            Ok(Received::Reply(response)) => response,
+            _ => panic!("expected a response"),
+        };
+        assert!(matches!(
+            client.receive(server_address, body(&response)),
+            Ok(Received::Nothing)
+        ));
+        assert!(!client.needs_handshake(server_address));
+
+        let encrypted = client.encrypt(server_address, b"secret".to_vec()).unwrap();
+        assert!(!encrypted.windows(6).any(|w| w == b"secret"));
+        match server.receive(client_address, body(&encrypted)) {
+            Ok(Received::Datagram(datagram)) => assert_eq!(b"secret".to_vec(), datagram),
+            _ => panic!("expected the datagram"),
+        }
+        assert!(matches!(
+            server.receive(client_address, body(&encrypted)),
+            Err(ChannelError::Replayed)
+        ));
+
+        let encrypted = server.encrypt(client_address, b"reply".to_vec()).unwrap();
+        match client.receive(server_address, body(&encrypted)) {
+            Ok(Received::Datagram(datagram)) => assert_eq!(b"reply".to_vec(), datagram),
+            _ => panic!("expected the datagram"),
+        }
+    }
+
+    /// Open a channel between the client and the server.
+    fn handshake(server: &Channels, client: &Channels) {
+        let initiation = client.initiate(peer(8080)).unwrap();
+        let response = match server.receive(peer(8081), body(&initiation)) {
+            Ok(Received::Reply(response)) => response,
+            _ => panic!("expected a response"),
+        };
+        assert!(matches!(
+            client.receive(peer(8080), body(&response)),
+            Ok(Received::Nothing)
+        ));
+    }
+
+    fn assert_delivered(from: &Channels, from_address: u16, to: &Channels, to_address: u16) {
+        let encrypted = from.encrypt(peer(to_address), b"data".to_vec()).unwrap();
+        match to.receive(peer(from_address), body(&encrypted)) {
+            Ok(Received::Datagram(datagram)) => assert_eq!(b"data".to_vec(), datagram),
+            _ => panic!("expected the datagram"),
+        }
+    }
+
+    #[test]
+    fn surviving_duplicate_initiations() {
+        let (private, public) = generate_keys();
+        let server = Channels::new(Mode::Respond(private));
+        let client = Channels::new(Mode::Initiate(public));
+
+        let initiation = client.initiate(peer(8080)).unwrap();
+        let response = match server.receive(peer(8081), body(&initiation)) {
+            Ok(Received::Reply(response)) => response,
+            _ => panic!("expected a response"),
+        };
+        client.receive(peer(8080), body(&response)).unwrap();
+        assert_delivered(&client, 8081, &server, 8080);
+
+        // The initiation arrives again, the server answers it but the client has no use for the
+        // answer and keeps its keys.
+        let late = match server.receive(peer(8081), body(&initiation)) {
+            Ok(Received::Reply(response)) => response,
+            _ => panic!("expected a response"),
+        };
+        assert!(client.receive(peer(8080), body(&late)).is_err());
+        assert_delivered(&server, 8080, &client, 8081);
+        assert_delivered(&client, 8081, &server, 8080);
+        assert_delivered(&server, 8080, &client, 8081);
+
+        // A new handshake replaces the channel once it's used.
+        handshake(&server, &client);
+        assert_delivered(&client, 8081, &server, 8080);
+        assert!(server.state.lock().unwrap().previous.is_empty());
+    }
+
+    #[test]
+    fn resetting_unknown_keys() {
+        let (private, public) = generate_keys();
+        let server = Channels::new(Mode::Respond(private.clone()));
+        let client = Channels::new(Mode::Initiate(public));
+        handshake(&server, &client);
+
+        // After a restart the server has other keys for the client's address, from a handshake
+        // that the client never saw.
+        let restarted = Channels::new(Mode::Respond(private));
+        handshake(&restarted, &Channels::new(Mode::Initiate(public)));
+        let encrypted = client.encrypt(peer(8080), b"data".to_vec()).unwrap();
+        let unknown = match restarted.receive(peer(8081), body(&encrypted)) {
+            Ok(Received::Reply(unknown)) => unknown,
+            _ => panic!("expected a reply"),
+        };
+        assert!(matches!(
+            client.receive(peer(8080), body(&unknown)),
+            Ok(Received::Reset)
+        ));
+        assert!(client.needs_handshake(peer(8080)));
+    }
+
+    #[test]
+    fn limiting_handshakes() {
+        let (private, public) = generate_keys();
+        let server = Channels::new(Mode::Respond(private));
+        let client = Channels::new(Mode::Initiate(public));
+
+        // The server answers the burst at once, then more as the bucket refills.
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: HANDSHAKE_BURST,
+            refilled: start,
+        };
+        let taken = |bucket: &mut Bucket, at| {
+            (0..2 * HANDSHAKE_BURST as usize)
+                .filter(|_| bucket.take_at(at))
+                .count()
+        };
+        assert_eq!(HANDSHAKE_BURST as usize, taken(&mut bucket, start));
+        let half = start + Duration::from_millis(500);
+        assert_eq!(
+            (HANDSHAKES_PER_SECOND / 2.0) as usize,
+            taken(&mut bucket, half)
+        );
+        let later = half + Duration::from_secs(60);
+        assert_eq!(HANDSHAKE_BURST as usize, taken(&mut bucket, later));
+
+        // Once it's empty, handshakes are turned away.
+        server.state.lock().unwrap().handshakes = Bucket {
+            tokens: 0.0,
+            refilled: Instant::now() + Duration::from_secs(3600),
+        };
+        let initiation = client.initiate(peer(8080)).unwrap();
+        assert!(matches!(
+            server.receive(peer(8080), body(&initiation)),
+            Err(ChannelError::Busy)
+        ));
+    }
+
+    #[test]
+    fn rejecting_other_servers() {
+        let (_, public) = generate_keys();
+        let (private, _) = generate_keys();
+        let server = Channels::new(Mode::Respond(private));
+        let client = Channels::new(Mode::Initiate(public));
+
+        let initiation = client.initiate(peer(8080)).unwrap();
+        assert!(matches!(
+            server.receive(peer(8081), body(&initiation)),
+            Err(ChannelError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn windows() {
+        let mut window = Window::default();
+        for &nonce in &[0, 3, 1, 200] {
+            assert!(window.is_new(nonce));
+            window.mark(nonce);
+            assert!(!window.is_new(nonce));
+        }
+        assert!(window.is_new(199));
+        assert!(!window.is_new(3));
+
+        let key = "00ff".repeat(KEY_SIZE / 2);
+        assert_eq!(key, key.parse::<PublicKey>().unwrap().to_hex());
+        assert!("00ff".parse::<PublicKey>().is_err());
+    }
+}
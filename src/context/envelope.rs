@@ -0,0 +1,254 @@
+use std::{convert::TryInto, fmt};
+
+use serde::{Deserialize, Serialize};
+
+/// Every datagram starts with these bytes, so stray packets are easy to tell apart.
+pub const MAGIC: [u8; 4] = *b"DSCH";
+/// The newest version of the protocol. Bump it whenever the encoding of a [super::Event] changes.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// The oldest version of the protocol that is still understood.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+/// The optional features this build supports, as [features] bits.
+pub const FEATURES: u8 = features::LZ4;
+
+/// The magic, the version and the flags.
+pub(crate) const HEADER_SIZE: usize = 6;
+/// Where the flags are in the datagram.
+pub(crate) const FLAGS_OFFSET: usize = 5;
+
+/// The bits of the flags byte in the envelope.
+pub mod flags {
+    /// The datagram is a [super::Hello] instead of a fragment of an event.
+    pub const HELLO: u8 = 1;
+    /// The tag of the [crate::context::Codec] that the event was encoded with.
+    pub const CODEC: u8 = 0b110;
+    pub(crate) const CODEC_SHIFT: u8 = 1;
+    /// The event was compressed with LZ4, only sent to peers that agreed to [super::features::LZ4].
+    pub const COMPRESSED: u8 = 0b1000;
+    /// The datagram ends with a timestamp, a nonce and a tag from the pre-shared
+    /// [crate::context::Key].
+    pub const AUTH: u8 = 0b1_0000;
+    /// The datagram is part of a channel encrypted with the server's static key, the datagram
+    /// inside has an envelope of its own.
+    pub const ENCRYPTED: u8 = 0b10_0000;
+}
+
+/// The optional features that are agreed on in the handshake.
+pub mod features {
+    /// Large events can be compressed with LZ4.
+    pub const LZ4: u8 = 1;
+}
+
+/// The envelope that every datagram is wrapped in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub(crate) version: u8,
+    pub(crate) flags: u8,
+}
+
+impl Header {
+    /// A header for a fragment of an event encoded with the codec.
+    pub(crate) fn event(version: u8, codec: u8, compressed: bool) -> Header {
+        let mut flags = (codec << flags::CODEC_SHIFT) & flags::CODEC;
+        if compressed {
+            flags |= flags::COMPRESSED;
+        }
+        Header { version, flags }
+    }
+
+    pub(crate) fn codec(self) -> u8 {
+        (self.flags & flags::CODEC) >> flags::CODEC_SHIFT
+    }
+
+    pub(crate) fn is_compressed(self) -> bool {
+        self.flags & flags::COMPRESSED != 0
+    }
+
+    pub(crate) fn is_authenticated(self) -> bool {
+        self.flags & flags::AUTH != 0
+    }
+
+    pub(crate) fn is_encrypted(self) -> bool {
+        self.flags & flags::ENCRYPTED != 0
+    }
+
+    pub(crate) fn wrap(self, body: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_SIZE + body.len());
+        out.extend_from_slice(&MAGIC);
+        out.push(self.version);
+        out.push(self.flags);
+        out.extend_from_slice(body);
+        out
+    }
+
+    /// Split a datagram into its envelope and the body inside of it.
+    pub(crate) fn open(datagram: &[u8]) -> Result<(Header, &[u8]), EnvelopeError> {
+        if datagram.len() < HEADER_SIZE || datagram[..4] != MAGIC {
+            return Err(EnvelopeError::NotOurs);
+        }
+
+        let header = Header {
+            version: datagram[4],
+            flags: datagram[FLAGS_OFFSET],
+        };
+        Ok((header, &datagram[HEADER_SIZE..]))
+    }
+}
+
+/// A datagram that can't be opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EnvelopeError {
+    /// The datagram doesn't start with the [MAGIC].
+    NotOurs,
+    /// The body of a hello is invalid.
+    InvalidHello,
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::NotOurs => write!(f, "no protocol magic"),
+            EnvelopeError::InvalidHello => write!(f, "invalid hello"),
+        }
+    }
+}
+
+/// The handshake that agrees on a version. Its layout never changes, so that nodes of any
+/// version can still tell each other why they can't talk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Hello {
+    /// The versions and features that the sender supports.
+    Hello { min: u8, max: u8, features: u8 },
+    /// The version and features to use from now on.
+    Accept { version: u8, features: u8 },
+    /// The versions that the sender supports, none of which the other end does.
+    Reject { min: u8, max: u8 },
+}
+
+impl Hello {
+    /// The hello for this build, offering the features.
+    pub(crate) fn ours(features: u8) -> Hello {
+        Hello::Hello {
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+            features,
+        }
+    }
+
+    /// Reply to a hello, picking the newest version and the features that both ends offer.
+    pub(crate) fn reply(min: u8, max: u8, features: u8, ours: u8) -> Hello {
+        let version = max.min(PROTOCOL_VERSION);
+        if version < min.max(MIN_PROTOCOL_VERSION) {
+            return Hello::Reject {
+                min: MIN_PROTOCOL_VERSION,
+                max: PROTOCOL_VERSION,
+            };
+        }
+
+        Hello::Accept {
+            version,
+            features: features & ours,
+        }
+    }
+
+    pub(crate) fn encode(self) -> Vec<u8> {
+        let body = match self {
+            Hello::Hello { min, max, features } => [0, min, max, features],
+            Hello::Accept { version, features } => [1, version, features, 0],
+            Hello::Reject { min, max } => [2, min, max, 0],
+        };
+
+        Header {
+            version: PROTOCOL_VERSION,
+            flags: flags::HELLO,
+        }
+        .wrap(&body)
+    }
+
+    pub(crate) fn decode(body: &[u8]) -> Result<Hello, EnvelopeError> {
+        let body: [u8; 4] = body.try_into().map_err(|_| EnvelopeError::InvalidHello)?;
+        match body {
+            [0, min, max, features] => Ok(Hello::Hello { min, max, features }),
+            [1, version, features, _] => Ok(Hello::Accept { version, features }),
+            [2, min, max, _] => Ok(Hello::Reject { min, max }),
+            _ => Err(EnvelopeError::InvalidHello),
+        }
+    }
+}
+
+/// How the hello with the other end went.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Handshake {
+    /// Both ends agreed on the version and features.
+    Accepted { version: u8, features: u8 },
+    /// The other end only supports the versions from `min` to `max`.
+    Rejected { min: u8, max: u8 },
+}
+
+impl fmt::Display for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Handshake::Accepted { version, .. } => {
+                write!(f, "Connected with protocol v{}", version)
+            }
+            Handshake::Rejected { min, max } => write!(
+                f,
+                "Incompatible: the other end speaks protocol v{}-v{}, this build speaks v{}-v{}",
+                min, max, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opening_envelopes() {
+        let header = Header {
+            version: PROTOCOL_VERSION,
+            flags: 0,
+        };
+        let datagram = header.wrap(b"body");
+        assert_eq!(Ok((header, &b"body"[..])), Header::open(&datagram));
+
+        assert_eq!(Err(EnvelopeError::NotOurs), Header::open(b"garbage"));
+
+        let header = Header::event(PROTOCOL_VERSION, 2, true);
+        assert_eq!(2, header.codec());
+        assert!(header.is_compressed());
+        assert!(!Header::event(PROTOCOL_VERSION, 3, false).is_compressed());
+    }
+
+    #[test]
+    fn negotiating_versions() {
+        assert_eq!(
+            Hello::Accept {
+                version: PROTOCOL_VERSION,
+                features: FEATURES,
+            },
+            Hello::reply(MIN_PROTOCOL_VERSION, u8::MAX, u8::MAX, FEATURES)
+        );
+        assert_eq!(
+            Hello::Accept {
+                version: PROTOCOL_VERSION,
+                features: 0,
+            },
+            Hello::reply(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, FEATURES, 0)
+        );
+        assert_eq!(
+            Hello::Reject {
+                min: MIN_PROTOCOL_VERSION,
+                max: PROTOCOL_VERSION,
+            },
+            Hello::reply(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2, 0, FEATURES)
+        );
+
+        let hello = Hello::ours(FEATURES);
+        let datagram = hello.encode();
+        let (header, body) = Header::open(&datagram).unwrap();
+        assert_eq!(flags::HELLO, header.flags);
+        assert_eq!(Ok(hello), Hello::decode(body));
+    }
+}
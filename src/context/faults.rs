@@ -0,0 +1,231 @@
+use std::{fmt, io, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+
+use futures::{future::BoxFuture, FutureExt};
+use rand::Rng;
+use tokio::{runtime::Handle, time::sleep};
+
+use super::link::Link;
+
+/// How much later than the rest a datagram that is held back is sent, so the ones sent after it
+/// overtake it.
+pub const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// What goes wrong with the datagrams that are sent, to see how the nodes cope with a bad
+/// network. Each datagram is dropped, delayed, duplicated and held back on its own.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Faults {
+    /// The chance that a datagram is dropped, from 0 to 1.
+    pub drop: f64,
+    /// How long every datagram is delayed.
+    pub delay: Delay,
+    /// The chance that a datagram is sent twice.
+    pub duplicate: f64,
+    /// The chance that a datagram is held back for [REORDER_DELAY].
+    pub reorder: f64,
+}
+
+impl Faults {
+    /// Whether every datagram is sent as is.
+    pub fn is_none(&self) -> bool {
+        self.drop <= 0.0
+            && self.delay == Delay::default()
+            && self.duplicate <= 0.0
+            && self.reorder <= 0.0
+    }
+}
+
+/// A delay picked evenly between the shortest and the longest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Delay {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl Delay {
+    fn sample<R: Rng>(&self, rng: &mut R) -> Duration {
+        if self.max <= self.min {
+            self.min
+        } else {
+            rng.gen_range(self.min..=self.max)
+        }
+    }
+}
+
+/// Milliseconds, like `20`, or a range of them, like `10-200`.
+impl FromStr for Delay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let millis = |s: &str| {
+            s.trim()
+                .parse()
+                .map(Duration::from_millis)
+                .map_err(|_| format!("{} is not a number of milliseconds", s))
+        };
+        let delay = match s.find('-') {
+            Some(i) => Delay {
+                min: millis(&s[..i])?,
+                max: millis(&s[i + 1..])?,
+            },
+            None => Delay {
+                min: millis(s)?,
+                max: millis(s)?,
+            },
+        };
+        if delay.min > delay.max {
+            return Err(format!("the range {} ends before it starts", s));
+        }
+        Ok(delay)
+    }
+}
+
+impl fmt::Display for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.min.as_millis())
+        } else {
+            write!(f, "{}-{}", self.min.as_millis(), self.max.as_millis())
+        }
+    }
+}
+
+/// Sends over another link, with the [Faults]. Receiving is left as is, so put the faults on both
+/// ends to have them both ways.
+pub(crate) struct Faulty {
+    link: Arc<dyn Link>,
+    faults: Faults,
+}
+
+impl Faulty {
+    pub(crate) fn new(link: Arc<dyn Link>, faults: Faults) -> Faulty {
+        Faulty { link, faults }
+    }
+}
+
+impl Link for Faulty {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.link.local_addr()
+    }
+
+    fn max_datagram(&self) -> usize {
+        self.link.max_datagram()
+    }
+
+    fn send_to<'a>(
+        &'a self,
+        datagram: &'a [u8],
+        peer: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let mut rng = rand::thread_rng();
+        if rng.gen::<f64>() < self.faults.drop {
+            return async { Ok(()) }.boxed();
+        }
+        let copies = if rng.gen::<f64>() < self.faults.duplicate {
+            2
+        } else {
+            1
+        };
+        let delays: Vec<_> = (0..copies)
+            .map(|_| {
+                let delay = self.faults.delay.sample(&mut rng);
+                if rng.gen::<f64>() < self.faults.reorder {
+                    delay + REORDER_DELAY
+                } else {
+                    delay
+                }
+            })
+            .collect();
+
+        async move {
+            for delay in delays {
+                if delay == Duration::default() {
+                    self.link.send_to(datagram, peer).await?;
+                    continue;
+                }
+
+                // Nothing waits for delayed datagrams, so whatever is sent after them can overtake
+                // them. Failures are lost with the datagram, like on the network.
+                let link = Arc::clone(&self.link);
+                let datagram = datagram.to_vec();
+                Handle::current().spawn(async move {
+                    sleep(delay).await;
+                    let _ = link.send_to(&datagram, peer).await;
+                });
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn recv_from(&self) -> BoxFuture<'_, io::Result<(Vec<u8>, SocketAddr)>> {
+        self.link.recv_from()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{select, Either};
+
+    use super::*;
+    use crate::context::{memory::Memory, Network};
+
+    #[test]
+    fn parsing_delays() {
+        let delay: Delay = "10-200".parse().unwrap();
+        assert_eq!(Duration::from_millis(10), delay.min);
+        assert_eq!(Duration::from_millis(200), delay.max);
+        assert_eq!("10-200", delay.to_string());
+        assert_eq!("20", "20".parse::<Delay>().unwrap().to_string());
+
+        assert!("200-10".parse::<Delay>().is_err());
+        assert!("soon".parse::<Delay>().is_err());
+        assert!(Faults::default().is_none());
+    }
+
+    /// Two nodes, the first sending with the faults.
+    fn pair(faults: Faults) -> (Faulty, Memory) {
+        let network = Network::new();
+        let sender = Memory::bind(&network, "[::1]:8080".parse().unwrap()).unwrap();
+        let receiver = Memory::bind(&network, "[::1]:8081".parse().unwrap()).unwrap();
+        (Faulty::new(Arc::new(sender), faults), receiver)
+    }
+
+    /// The next datagram, unless nothing arrives for a while.
+    async fn next(receiver: &Memory) -> Option<Vec<u8>> {
+        let timeout = sleep(Duration::from_millis(200));
+        match select(receiver.recv_from(), Box::pin(timeout)).await {
+            Either::Left((received, _)) => Some(received.unwrap().0),
+            Either::Right(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn injecting_faults() {
+        let address = "[::1]:8081".parse().unwrap();
+
+        let (sender, receiver) = pair(Faults {
+            drop: 1.0,
+            ..Faults::default()
+        });
+        sender.send_to(b"lost", address).await.unwrap();
+        assert_eq!(None, next(&receiver).await);
+
+        let (sender, receiver) = pair(Faults {
+            duplicate: 1.0,
+            ..Faults::default()
+        });
+        sender.send_to(b"twice", address).await.unwrap();
+        assert_eq!(Some(b"twice".to_vec()), next(&receiver).await);
+        assert_eq!(Some(b"twice".to_vec()), next(&receiver).await);
+        assert_eq!(None, next(&receiver).await);
+
+        let (sender, receiver) = pair(Faults {
+            reorder: 1.0,
+            ..Faults::default()
+        });
+        sender.send_to(b"first", address).await.unwrap();
+        sender.link.send_to(b"second", address).await.unwrap();
+        assert_eq!(Some(b"second".to_vec()), next(&receiver).await);
+        assert_eq!(Some(b"first".to_vec()), next(&receiver).await);
+    }
+}
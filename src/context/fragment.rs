@@ -0,0 +1,301 @@
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    fmt,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use super::MAX_MESSAGE_SIZE;
+
+/// Every fragment starts with the id of its message, its index and the number of fragments in
+/// the message.
+pub(crate) const HEADER_SIZE: usize = 12;
+/// The most fragments that a message can be split into.
+pub(crate) const MAX_FRAGMENTS: usize = 1024;
+/// The most messages that can be partly received at once, across all peers.
+const MAX_PENDING: usize = 256;
+/// The most messages that can be partly received from one peer at once, so that one peer can't
+/// take all of the [MAX_PENDING] slots.
+const MAX_PENDING_PER_PEER: usize = 8;
+/// The most bytes of partly received messages from one peer.
+const MAX_BUFFERED_PER_PEER: usize = 2 * MAX_MESSAGE_SIZE;
+
+/// A fragment that can't be reassembled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FragmentError {
+    /// The datagram is too short to have a header.
+    Truncated,
+    /// The index or count in the header is out of range.
+    InvalidHeader,
+    /// The count doesn't match the other fragments of the message.
+    CountMismatch,
+    /// Too many messages are partly received already, from the peer or from everyone.
+    TooManyPending,
+    /// Too many bytes of the peer's messages are buffered already.
+    TooMuchBuffered,
+    /// The message is larger than [MAX_MESSAGE_SIZE].
+    TooLarge,
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FragmentError::Truncated => write!(f, "too short for a fragment header"),
+            FragmentError::InvalidHeader => write!(f, "invalid fragment index or count"),
+            FragmentError::CountMismatch => {
+                write!(f, "fragment count differs from the rest of the message")
+            }
+            FragmentError::TooManyPending => write!(f, "too many partly received messages"),
+            FragmentError::TooMuchBuffered => {
+                write!(f, "too many bytes of partly received messages")
+            }
+            FragmentError::TooLarge => write!(f, "larger than {} bytes", MAX_MESSAGE_SIZE),
+        }
+    }
+}
+
+/// Split the payload into datagrams of at most the given size, each with a fragment header.
+/// Returns nothing if the payload needs more than [MAX_FRAGMENTS].
+pub(crate) fn split(id: u64, payload: &[u8], max_datagram: usize) -> Option<Vec<Vec<u8>>> {
+    let piece_size = max_datagram - HEADER_SIZE;
+    // An empty payload is still sent as one empty fragment.
+    let count = (payload.len() / piece_size + (payload.len() % piece_size != 0) as usize).max(1);
+    if count > MAX_FRAGMENTS {
+        return None;
+    }
+
+    let pieces = (0..count).map(|index| {
+        let start = index * piece_size;
+        let end = payload.len().min(start + piece_size);
+
+        let mut datagram = Vec::with_capacity(HEADER_SIZE + end - start);
+        datagram.extend_from_slice(&id.to_le_bytes());
+        datagram.extend_from_slice(&(index as u16).to_le_bytes());
+        datagram.extend_from_slice(&(count as u16).to_le_bytes());
+        datagram.extend_from_slice(&payload[start..end]);
+        datagram
+    });
+
+    Some(pieces.collect())
+}
+
+/// A message that is missing some of its fragments.
+struct Partial {
+    pieces: Vec<Option<Vec<u8>>>,
+    received: usize,
+    /// The bytes received so far.
+    size: usize,
+    started: Instant,
+}
+
+/// Puts the fragments from each peer back together into messages. Messages that aren't complete
+/// within the timeout are dropped.
+pub(crate) struct Reassembler {
+    partial: BTreeMap<(SocketAddr, u64), Partial>,
+    timeout: Duration,
+}
+
+impl Reassembler {
+    pub(crate) fn new(timeout: Duration) -> Reassembler {
+        Reassembler {
+            partial: BTreeMap::new(),
+            timeout,
+        }
+    }
+
+    /// Add a received fragment. Returns the message once all of its fragments are received.
+    pub(crate) fn add(
+        &mut self,
+        peer: SocketAddr,
+        datagram: &[u8],
+    ) -> Result<Option<Vec<u8>>, FragmentError> {
+        if datagram.len() < HEADER_SIZE {
+            return Err(FragmentError::Truncated);
+        }
+        let id = u64::from_le_bytes(datagram[..8].try_into().unwrap());
+        let index = u16::from_le_bytes(datagram[8..10].try_into().unwrap()) as usize;
+        let count = u16::from_le_bytes(datagram[10..12].try_into().unwrap()) as usize;
+        let data = &datagram[HEADER_SIZE..];
+
+        if count == 0 || count > MAX_FRAGMENTS || index >= count {
+            return Err(FragmentError::InvalidHeader);
+        }
+
+        if data.len() > MAX_MESSAGE_SIZE {
+            return Err(FragmentError::TooLarge);
+        }
+        // Most messages fit in one datagram, so skip the bookkeeping.
+        if count == 1 {
+            return Ok(Some(data.to_vec()));
+        }
+
+        let (pending, buffered) = self
+            .partial
+            .range((peer, 0)..=(peer, u64::MAX))
+            .fold((0, 0), |(pending, buffered), (_, partial)| {
+                (pending + 1, buffered + partial.size)
+            });
+        if !self.partial.contains_key(&(peer, id))
+            && (self.partial.len() >= MAX_PENDING || pending >= MAX_PENDING_PER_PEER)
+        {
+            return Err(FragmentError::TooManyPending);
+        }
+        if buffered + data.len() > MAX_BUFFERED_PER_PEER {
+            return Err(FragmentError::TooMuchBuffered);
+        }
+        let partial = self.partial.entry((peer, id)).or_insert_with(|| Partial {
+            pieces: vec![None; count],
+            received: 0,
+            size: 0,
+            started: Instant::now(),
+        });
+        if partial.pieces.len() != count {
+            return Err(FragmentError::CountMismatch);
+        }
+
+        // Duplicated fragments are ignored.
+        if partial.pieces[index].is_none() {
+            if partial.size + data.len() > MAX_MESSAGE_SIZE {
+                self.partial.remove(&(peer, id));
+                return Err(FragmentError::TooLarge);
+            }
+            partial.pieces[index] = Some(data.to_vec());
+            partial.received += 1;
+            partial.size += data.len();
+        }
+        if partial.received < count {
+            return Ok(None);
+        }
+
+        let partial = self.partial.remove(&(peer, id)).unwrap();
+        Ok(Some(
+            partial.pieces.into_iter().flatten().flatten().collect(),
+        ))
+    }
+
+    /// Drop the messages that have been waiting on fragments for longer than the timeout, and
+    /// return the peers that sent them.
+    pub(crate) fn expire(&mut self) -> Vec<SocketAddr> {
+        let timeout = self.timeout;
+        let expired: Vec<_> = self
+            .partial
+            .iter()
+            .filter(|(_, partial)| partial.started.elapsed() >= timeout)
+            .map(|(&key, _)| key)
+            .collect();
+
+        for key in &expired {
+            self.partial.remove(key);
+        }
+        expired.into_iter().map(|(peer, _)| peer).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        "[::1]:8080".parse().unwrap()
+    }
+
+    #[test]
+    fn reassembling_out_of_order() {
+        let payload: Vec<u8> = (0..100).collect();
+        let mut fragments = split(7, &payload, HEADER_SIZE + 30).unwrap();
+        assert_eq!(4, fragments.len());
+
+        fragments.reverse();
+        let duplicate = fragments[0].clone();
+        fragments.insert(1, duplicate);
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+        let (last, rest) = fragments.split_last().unwrap();
+        for fragment in rest {
+            assert_eq!(Ok(None), reassembler.add(peer(), fragment));
+        }
+        assert_eq!(Ok(Some(payload)), reassembler.add(peer(), last));
+    }
+
+    #[test]
+    fn invalid_fragments() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+        assert_eq!(
+            Err(FragmentError::Truncated),
+            reassembler.add(peer(), b"short")
+        );
+
+        let mut fragment = split(1, b"data", 100).unwrap().remove(0);
+        fragment[8] = 1;
+        assert_eq!(
+            Err(FragmentError::InvalidHeader),
+            reassembler.add(peer(), &fragment)
+        );
+
+        assert!(split(1, &vec![0; 2 * MAX_FRAGMENTS], HEADER_SIZE + 1).is_none());
+    }
+
+    #[test]
+    fn limiting_each_peer() {
+        let other: SocketAddr = "[::1]:8081".parse().unwrap();
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+        let first = |id| split(id, &[0; 100], HEADER_SIZE + 50).unwrap().remove(0);
+        for id in 0..MAX_PENDING_PER_PEER as u64 {
+            assert_eq!(Ok(None), reassembler.add(peer(), &first(id)));
+        }
+        assert_eq!(
+            Err(FragmentError::TooManyPending),
+            reassembler.add(peer(), &first(100))
+        );
+        // Other peers still have room.
+        assert_eq!(Ok(None), reassembler.add(other, &first(100)));
+
+        // Large fragments run into the byte limit first.
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+        let half = |id| {
+            split(
+                id,
+                &vec![0; MAX_MESSAGE_SIZE],
+                HEADER_SIZE + MAX_MESSAGE_SIZE / 2,
+            )
+            .unwrap()
+            .remove(0)
+        };
+        for id in 0..4 {
+            assert_eq!(Ok(None), reassembler.add(peer(), &half(id)));
+        }
+        assert_eq!(
+            Err(FragmentError::TooMuchBuffered),
+            reassembler.add(peer(), &half(4))
+        );
+    }
+
+    #[test]
+    fn rejecting_large_messages() {
+        // Few fragments, but each of them large, like over TCP.
+        let payload = vec![0; MAX_MESSAGE_SIZE + 1];
+        let fragments = split(1, &payload, HEADER_SIZE + (1 << 16)).unwrap();
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+        let (last, rest) = fragments.split_last().unwrap();
+        for fragment in rest {
+            assert_eq!(Ok(None), reassembler.add(peer(), fragment));
+        }
+        assert_eq!(Err(FragmentError::TooLarge), reassembler.add(peer(), last));
+        // What was received of it is dropped.
+        assert!(reassembler.partial.is_empty());
+    }
+
+    #[test]
+    fn expiring_lost_fragments() {
+        let fragments = split(1, &[0; 100], HEADER_SIZE + 50).unwrap();
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(0));
+        assert_eq!(Ok(None), reassembler.add(peer(), &fragments[0]));
+        assert_eq!(vec![peer()], reassembler.expire());
+
+        // The rest of the message starts over instead of completing it.
+        assert_eq!(Ok(None), reassembler.add(peer(), &fragments[1]));
+    }
+}
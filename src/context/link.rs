@@ -0,0 +1,111 @@
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::{fmt, io, net::SocketAddr, str::FromStr};
+
+use futures::future::BoxFuture;
+
+#[cfg(unix)]
+use super::unix::Unix;
+use super::{
+    memory::{Memory, Network},
+    tcp::Tcp,
+    udp::Udp,
+};
+
+/// Carries datagrams between nodes, which are known by their address. Everything above it, the
+/// envelope, fragments, compression, authentication and encryption, is the same for every link.
+pub(crate) trait Link: Send + Sync {
+    /// The address that other nodes know this one by.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// The largest datagram that can be sent, larger events are split into fragments.
+    fn max_datagram(&self) -> usize;
+
+    fn send_to<'a>(&'a self, datagram: &'a [u8], peer: SocketAddr)
+        -> BoxFuture<'a, io::Result<()>>;
+
+    /// Wait for the next datagram, and the address of the node that sent it. Datagrams larger
+    /// than [Link::max_datagram] can be returned, to be dropped as oversized.
+    fn recv_from(&self) -> BoxFuture<'_, io::Result<(Vec<u8>, SocketAddr)>>;
+}
+
+/// How datagrams get to the other end. All but [Transport::Memory] can be picked with
+/// `--transport`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// Datagrams as they are, the default.
+    Udp,
+    /// A connection to each peer, with every datagram in a length-prefixed frame. Connections
+    /// are opened again when they close. Gets through firewalls that block UDP, and fits much
+    /// larger datagrams.
+    Tcp,
+    /// A socket in the directory, for nodes on the same host. The address of a node only names
+    /// its socket, and the permissions on the sockets decide who can send to them.
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// Straight to the other nodes on the network, inside of this process.
+    Memory(Network),
+}
+
+impl Transport {
+    pub(crate) fn bind(&self, address: SocketAddr) -> io::Result<Box<dyn Link>> {
+        Ok(match self {
+            Transport::Udp => Box::new(Udp::bind(address)?),
+            Transport::Tcp => Box::new(Tcp::bind(address)?),
+            #[cfg(unix)]
+            Transport::Unix(directory) => Box::new(Unix::bind(directory, address)?),
+            Transport::Memory(network) => Box::new(Memory::bind(network, address)?),
+        })
+    }
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport::Udp
+    }
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "udp" => Ok(Transport::Udp),
+            "tcp" => Ok(Transport::Tcp),
+            #[cfg(unix)]
+            _ if s.starts_with("unix:") && s.len() > "unix:".len() => {
+                Ok(Transport::Unix(PathBuf::from(&s["unix:".len()..])))
+            }
+            _ => Err(format!(
+                "{} is not a transport, use udp, tcp or unix:<directory>",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Udp => write!(f, "udp"),
+            Transport::Tcp => write!(f, "tcp"),
+            #[cfg(unix)]
+            Transport::Unix(directory) => write!(f, "unix:{}", directory.display()),
+            Transport::Memory(_) => write!(f, "memory"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing_transports() {
+        for name in &["udp", "tcp", "unix:/run/chat"] {
+            assert_eq!(*name, name.parse::<Transport>().unwrap().to_string());
+        }
+        assert!("quic".parse::<Transport>().is_err());
+        assert!("unix:".parse::<Transport>().is_err());
+    }
+}
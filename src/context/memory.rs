@@ -0,0 +1,160 @@
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    future::BoxFuture,
+    lock::Mutex as AsyncMutex,
+    FutureExt, StreamExt,
+};
+
+use super::{link::Link, udp::canonical, MAX_DATAGRAM_SIZE};
+
+/// Where a node that binds to port 0 gets its port from.
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// A network inside of the process, for running a server and its clients together, in tests or
+/// embedded in another program. Nodes that bind with a clone of the same network can talk to
+/// each other, and to nothing else. Addresses only have to be unique within the network.
+#[derive(Clone, Default)]
+pub struct Network {
+    nodes: Arc<Mutex<BTreeMap<SocketAddr, UnboundedSender<Datagram>>>>,
+}
+
+impl Network {
+    pub fn new() -> Network {
+        Network::default()
+    }
+}
+
+impl fmt::Debug for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nodes = self.nodes.lock().unwrap();
+        f.debug_set().entries(nodes.keys()).finish()
+    }
+}
+
+/// Clones of the same network are equal.
+impl PartialEq for Network {
+    fn eq(&self, other: &Network) -> bool {
+        Arc::ptr_eq(&self.nodes, &other.nodes)
+    }
+}
+
+impl Eq for Network {}
+
+/// A node on a [Network]. Datagrams are handed straight to the other node, in order and never
+/// lost, but they're still no larger than over UDP, so events are split the same way.
+pub(crate) struct Memory {
+    network: Network,
+    local: SocketAddr,
+    received: AsyncMutex<UnboundedReceiver<Datagram>>,
+}
+
+impl Memory {
+    pub(crate) fn bind(network: &Network, address: SocketAddr) -> io::Result<Memory> {
+        let mut local = canonical(address);
+        let mut nodes = network.nodes.lock().unwrap();
+        if local.port() == 0 {
+            let port = (FIRST_EPHEMERAL_PORT..=u16::MAX).find(|&port| {
+                local.set_port(port);
+                !nodes.contains_key(&local)
+            });
+            if port.is_none() {
+                return Err(io::ErrorKind::AddrNotAvailable.into());
+            }
+        } else if nodes.contains_key(&local) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+
+        let (sender, received) = unbounded();
+        nodes.insert(local, sender);
+        Ok(Memory {
+            network: network.clone(),
+            local,
+            received: AsyncMutex::new(received),
+        })
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        self.network.nodes.lock().unwrap().remove(&self.local);
+    }
+}
+
+impl Link for Memory {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn max_datagram(&self) -> usize {
+        MAX_DATAGRAM_SIZE
+    }
+
+    fn send_to<'a>(
+        &'a self,
+        datagram: &'a [u8],
+        peer: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let nodes = self.network.nodes.lock().unwrap();
+        // Like UDP, a datagram to a node that isn't there is lost without an error.
+        if let Some(node) = nodes.get(&canonical(peer)) {
+            let _ = node.unbounded_send((datagram.to_vec(), self.local));
+        }
+        async { Ok(()) }.boxed()
+    }
+
+    fn recv_from(&self) -> BoxFuture<'_, io::Result<(Vec<u8>, SocketAddr)>> {
+        async move {
+            let mut received = self.received.lock().await;
+            match received.next().await {
+                Some(datagram) => Ok(datagram),
+                // The sender is in the network until this is dropped, so this never ends.
+                None => Err(io::ErrorKind::NotConnected.into()),
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sending_datagrams() {
+        let network = Network::new();
+        let node1 = Memory::bind(&network, "[::1]:8080".parse().unwrap()).unwrap();
+        let node2 = Memory::bind(&network, "[::1]:0".parse().unwrap()).unwrap();
+        let address1 = node1.local_addr().unwrap();
+        let address2 = node2.local_addr().unwrap();
+        assert_eq!(FIRST_EPHEMERAL_PORT, address2.port());
+        assert!(Memory::bind(&network, address1).is_err());
+
+        node1.send_to(b"hello", address2).await.unwrap();
+        assert_eq!(
+            (b"hello".to_vec(), address1),
+            node2.recv_from().await.unwrap()
+        );
+
+        // Networks are separate.
+        let other = Memory::bind(&Network::new(), address1).unwrap();
+        node2.send_to(b"reply", address1).await.unwrap();
+        assert_eq!(
+            (b"reply".to_vec(), address2),
+            node1.recv_from().await.unwrap()
+        );
+        assert!(other.received.lock().await.try_next().is_err());
+
+        drop(node1);
+        node2.send_to(b"gone", address1).await.unwrap();
+        assert!(Memory::bind(&network, address1).is_ok());
+    }
+}
@@ -0,0 +1,423 @@
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    future::BoxFuture,
+    lock::Mutex as AsyncMutex,
+    FutureExt, StreamExt,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Handle,
+    time::{sleep, timeout},
+};
+
+use super::{link::Link, udp::canonical};
+
+/// The largest frame, and so the largest datagram.
+pub(crate) const MAX_FRAME_SIZE: usize = 1 << 20;
+/// The first frame on a connection is the port that the node which opened it listens on.
+const IDENTITY_SIZE: usize = 2;
+/// How long a new connection has to say who it's from.
+const IDENTITY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for a peer to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// The most bytes waiting to be written to one peer. Datagrams past this are dropped, like a full
+/// socket buffer drops them over UDP, rather than piling up while the peer doesn't read.
+const MAX_QUEUED_BYTES: usize = 4 * MAX_FRAME_SIZE;
+
+type Received = io::Result<(Vec<u8>, SocketAddr)>;
+
+/// The connections to each peer, by the address the peer listens on.
+type Connections = Arc<Mutex<BTreeMap<SocketAddr, Connection>>>;
+
+/// The frames waiting to be written to a peer.
+#[derive(Clone)]
+struct Connection {
+    frames: UnboundedSender<Vec<u8>>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl Connection {
+    fn send(&self, frame: &[u8]) -> io::Result<()> {
+        if self.queued.fetch_add(frame.len(), Ordering::Relaxed) + frame.len() > MAX_QUEUED_BYTES {
+            self.queued.fetch_sub(frame.len(), Ordering::Relaxed);
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "the peer isn't reading what is sent to it",
+            ));
+        }
+        self.frames.unbounded_send(frame.to_vec()).map_err(|_| {
+            self.queued.fetch_sub(frame.len(), Ordering::Relaxed);
+            io::Error::new(io::ErrorKind::BrokenPipe, "the connection closed")
+        })
+    }
+}
+
+/// Datagrams over TCP, each in a frame with its length in front. Every node listens on its
+/// address, and connects to a peer the first time it sends to it, or again after the connection
+/// closed. Both ends use the one connection, whichever opened it.
+///
+/// A connection is from the IP address it comes from, and the port that the first frame on it
+/// names, so nobody can claim another host's address. A new connection never replaces one that is
+/// still open, so nobody can take over a peer's connection either.
+pub(crate) struct Tcp {
+    local: SocketAddr,
+    connections: Connections,
+    received_sender: UnboundedSender<Received>,
+    received: AsyncMutex<UnboundedReceiver<Received>>,
+}
+
+impl Tcp {
+    pub(crate) fn bind(address: SocketAddr) -> io::Result<Tcp> {
+        let address = canonical(address);
+        let socket = Socket::new(
+            Domain::for_address(address),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        if address.is_ipv6() {
+            let _ = socket.set_only_v6(false);
+        }
+        // Restarting right away shouldn't fail on the connections of the last run.
+        socket.set_reuse_address(true)?;
+        socket.bind(&address.into())?;
+        socket.listen(1024)?;
+        socket.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(socket.into())?;
+
+        let (received_sender, received) = unbounded();
+        let tcp = Tcp {
+            local: canonical(listener.local_addr()?),
+            connections: Arc::default(),
+            received_sender,
+            received: AsyncMutex::new(received),
+        };
+
+        let connections = Arc::clone(&tcp.connections);
+        let received = tcp.received_sender.clone();
+        Handle::current().spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let connections = Arc::clone(&connections);
+                        let received = received.clone();
+                        Handle::current().spawn(async move {
+                            if let Err(e) = accept(stream, connections, received.clone()).await {
+                                let _ = received.unbounded_send(Err(e));
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        // Likely out of file descriptors, give some time for connections to close.
+                        let _ = received.unbounded_send(Err(e));
+                        sleep(Duration::from_millis(100)).await;
+                    }
+                }
+            }
+        });
+
+        Ok(tcp)
+    }
+
+    /// Open a connection to the peer, and tell it who we are.
+    async fn connect(&self, peer: SocketAddr) -> io::Result<Connection> {
+        let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(peer))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connecting timed out"))??;
+        write_frame(&mut stream, &self.local.port().to_le_bytes()).await?;
+        // Replaces a connection that closed, or one that the peer opened in the meantime.
+        let connection = register(
+            peer,
+            stream,
+            Arc::clone(&self.connections),
+            self.received_sender.clone(),
+        );
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(peer, connection.clone());
+        Ok(connection)
+    }
+}
+
+impl Link for Tcp {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn max_datagram(&self) -> usize {
+        MAX_FRAME_SIZE
+    }
+
+    fn send_to<'a>(
+        &'a self,
+        datagram: &'a [u8],
+        peer: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            let peer = canonical(peer);
+            let connection = {
+                let connections = self.connections.lock().unwrap();
+                connections
+                    .get(&peer)
+                    .filter(|c| !c.frames.is_closed())
+                    .cloned()
+            };
+            let connection = match connection {
+                Some(connection) => connection,
+                None => self.connect(peer).await?,
+            };
+
+            connection.send(datagram)
+        }
+        .boxed()
+    }
+
+    fn recv_from(&self) -> BoxFuture<'_, io::Result<(Vec<u8>, SocketAddr)>> {
+        async move {
+            let mut received = self.received.lock().await;
+            match received.next().await {
+                Some(received) => received,
+                // The sender is kept in self, so this never ends.
+                None => Err(io::ErrorKind::NotConnected.into()),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Find out who opened the connection, and use it unless there already is an open connection to
+/// the peer.
+async fn accept(
+    mut stream: TcpStream,
+    connections: Connections,
+    received: UnboundedSender<Received>,
+) -> io::Result<()> {
+    let identity = timeout(IDENTITY_TIMEOUT, read_frame(&mut stream, IDENTITY_SIZE))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no port on the new connection"))??;
+    let port: [u8; IDENTITY_SIZE] = identity.as_slice().try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid port on the new connection",
+        )
+    })?;
+    let peer = SocketAddr::new(
+        canonical(stream.peer_addr()?).ip(),
+        u16::from_le_bytes(port),
+    );
+
+    let mut open = connections.lock().unwrap();
+    if open.get(&peer).map_or(false, |c| !c.frames.is_closed()) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} opened a second connection", peer),
+        ));
+    }
+    let connection = register(peer, stream, Arc::clone(&connections), received);
+    open.insert(peer, connection);
+    Ok(())
+}
+
+/// Start writing the frames that are sent to the peer, and reading the ones it sends, until the
+/// connection closes. The caller adds the connection to the others.
+fn register(
+    peer: SocketAddr,
+    stream: TcpStream,
+    connections: Connections,
+    received: UnboundedSender<Received>,
+) -> Connection {
+    let _ = stream.set_nodelay(true);
+    let (mut reader, mut writer) = stream.into_split();
+    let (sender, mut frames) = unbounded::<Vec<u8>>();
+    let connection = Connection {
+        frames: sender,
+        queued: Arc::default(),
+    };
+
+    let queued = Arc::clone(&connection.queued);
+    Handle::current().spawn(async move {
+        while let Some(frame) = frames.next().await {
+            let written = write_frame(&mut writer, &frame).await;
+            queued.fetch_sub(frame.len(), Ordering::Relaxed);
+            if written.is_err() {
+                // The next send to the peer finds this closed and connects again.
+                frames.close();
+                break;
+            }
+        }
+    });
+
+    let reading = connection.frames.clone();
+    Handle::current().spawn(async move {
+        loop {
+            match read_frame(&mut reader, MAX_FRAME_SIZE).await {
+                Ok(frame) => {
+                    let _ = received.unbounded_send(Ok((frame, peer)));
+                }
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::UnexpectedEof {
+                        let _ = received.unbounded_send(Err(e));
+                    }
+                    break;
+                }
+            }
+        }
+        // Stops the writer too.
+        reading.close_channel();
+        close(&connections, peer, &reading);
+    });
+
+    connection
+}
+
+/// Forget the connection, unless it was already replaced by a newer one.
+fn close(connections: &Connections, peer: SocketAddr, connection: &UnboundedSender<Vec<u8>>) {
+    let mut connections = connections.lock().unwrap();
+    if connections
+        .get(&peer)
+        .map_or(false, |c| c.frames.same_receiver(connection))
+    {
+        connections.remove(&peer);
+    }
+}
+
+async fn write_frame<W>(writer: &mut W, frame: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = Vec::with_capacity(4 + frame.len());
+    buf.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    buf.extend_from_slice(frame);
+    writer.write_all(&buf).await
+}
+
+async fn read_frame<R>(reader: &mut R, max: usize) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut size = [0; 4];
+    reader.read_exact(&mut size).await?;
+    let size = u32::from_le_bytes(size) as usize;
+    if size > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("a frame of {} bytes is larger than {}", size, max),
+        ));
+    }
+
+    let mut frame = vec![0; size];
+    reader.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sending_frames() {
+        let tcp1 = Tcp::bind("[::1]:0".parse().unwrap()).unwrap();
+        let tcp2 = Tcp::bind("[::1]:0".parse().unwrap()).unwrap();
+        let address1 = tcp1.local_addr().unwrap();
+        let address2 = tcp2.local_addr().unwrap();
+
+        // Larger than fits in a UDP datagram.
+        let large = vec![7; 100_000];
+        tcp1.send_to(&large, address2).await.unwrap();
+        tcp1.send_to(b"second", address2).await.unwrap();
+        assert_eq!((large, address1), tcp2.recv_from().await.unwrap());
+        assert_eq!(
+            (b"second".to_vec(), address1),
+            tcp2.recv_from().await.unwrap()
+        );
+
+        // The reply goes back over the same connection.
+        tcp2.send_to(b"reply", address1).await.unwrap();
+        assert_eq!(
+            (b"reply".to_vec(), address2),
+            tcp1.recv_from().await.unwrap()
+        );
+        assert_eq!(1, tcp1.connections.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn reconnecting() {
+        let tcp1 = Tcp::bind("[::1]:0".parse().unwrap()).unwrap();
+        let tcp2 = Tcp::bind("[::1]:0".parse().unwrap()).unwrap();
+        let address2 = tcp2.local_addr().unwrap();
+
+        tcp1.send_to(b"first", address2).await.unwrap();
+        assert_eq!(b"first".to_vec(), tcp2.recv_from().await.unwrap().0);
+
+        // Close the connection from the other end.
+        let connection = tcp2.connections.lock().unwrap().values().next().cloned();
+        connection.unwrap().frames.close_channel();
+        let closed = async {
+            while !tcp1.connections.lock().unwrap().is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), closed).await.unwrap();
+
+        tcp1.send_to(b"again", address2).await.unwrap();
+        assert_eq!(b"again".to_vec(), tcp2.recv_from().await.unwrap().0);
+    }
+
+    #[tokio::test]
+    async fn refusing_to_replace_connections() {
+        let tcp1 = Tcp::bind("[::1]:0".parse().unwrap()).unwrap();
+        let tcp2 = Tcp::bind("[::1]:0".parse().unwrap()).unwrap();
+        let address1 = tcp1.local_addr().unwrap();
+        let address2 = tcp2.local_addr().unwrap();
+        tcp1.send_to(b"first", address2).await.unwrap();
+        assert_eq!(
+            (b"first".to_vec(), address1),
+            tcp2.recv_from().await.unwrap()
+        );
+
+        // Someone else on the host claims to be tcp1.
+        let mut impostor = TcpStream::connect(address2).await.unwrap();
+        write_frame(&mut impostor, &address1.port().to_le_bytes())
+            .await
+            .unwrap();
+        let refused = tcp2.recv_from().await.unwrap_err();
+        assert_eq!(io::ErrorKind::AlreadyExists, refused.kind());
+        assert_eq!(0, impostor.read(&mut [0; 1]).await.unwrap());
+
+        tcp2.send_to(b"reply", address1).await.unwrap();
+        assert_eq!(
+            (b"reply".to_vec(), address2),
+            tcp1.recv_from().await.unwrap()
+        );
+    }
+
+    #[test]
+    fn limiting_the_queue() {
+        let (frames, _unread) = unbounded();
+        let connection = Connection {
+            frames,
+            queued: Arc::default(),
+        };
+        let frame = vec![0; MAX_FRAME_SIZE];
+        for _ in 0..MAX_QUEUED_BYTES / MAX_FRAME_SIZE {
+            connection.send(&frame).unwrap();
+        }
+        let full = connection.send(&frame).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, full.kind());
+        assert_eq!(MAX_QUEUED_BYTES, connection.queued.load(Ordering::Relaxed));
+    }
+}
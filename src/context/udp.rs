@@ -0,0 +1,142 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use super::{link::Link, MAX_DATAGRAM_SIZE};
+
+/// A UDP socket that talks to IPv4 and IPv6 peers alike. Peers are always given as plain IPv4
+/// or IPv6 addresses, never as IPv4-mapped IPv6 addresses, so each peer has one address.
+#[derive(Debug)]
+pub(crate) struct Udp {
+    socket: UdpSocket,
+    ipv6: bool,
+}
+
+impl Udp {
+    /// Bind to the address. An IPv6 socket also receives from IPv4 peers where the system
+    /// allows it, so `[::]` listens on both.
+    pub(crate) fn bind(address: SocketAddr) -> io::Result<Udp> {
+        let address = canonical(address);
+        let socket = Socket::new(
+            Domain::for_address(address),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        if address.is_ipv6() {
+            // Not every system supports dual-stack sockets, IPv6 still works without it.
+            let _ = socket.set_only_v6(false);
+        }
+        socket.bind(&address.into())?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Udp {
+            socket: UdpSocket::from_std(socket.into())?,
+            ipv6: address.is_ipv6(),
+        })
+    }
+}
+
+impl Link for Udp {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr().map(canonical)
+    }
+
+    fn max_datagram(&self) -> usize {
+        MAX_DATAGRAM_SIZE
+    }
+
+    fn send_to<'a>(
+        &'a self,
+        datagram: &'a [u8],
+        peer: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let peer = match (canonical(peer), self.ipv6) {
+            (SocketAddr::V4(peer), true) => {
+                SocketAddr::new(IpAddr::V6(peer.ip().to_ipv6_mapped()), peer.port())
+            }
+            (SocketAddr::V6(peer), false) => {
+                let error = io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("can't reach the IPv6 address {} from IPv4", peer),
+                );
+                return async move { Err(error) }.boxed();
+            }
+            (peer, _) => peer,
+        };
+
+        async move { self.socket.send_to(datagram, peer).await.map(|_| ()) }.boxed()
+    }
+
+    fn recv_from(&self) -> BoxFuture<'_, io::Result<(Vec<u8>, SocketAddr)>> {
+        async move {
+            // One extra byte to tell when a datagram is too large.
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE + 1];
+            let (size, peer) = self.socket.recv_from(&mut buf).await?;
+            buf.truncate(size);
+            Ok((buf, canonical(peer)))
+        }
+        .boxed()
+    }
+}
+
+/// The plain IPv4 address for an IPv4-mapped IPv6 address, any other address as is.
+pub(crate) fn canonical(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V6(ip) => match ipv4_mapped(ip) {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip.into()), address.port()),
+            None => address,
+        },
+        IpAddr::V4(_) => address,
+    }
+}
+
+/// `Ipv6Addr::to_ipv4` also converts IPv4-compatible addresses, like `::1`.
+fn ipv4_mapped(ip: Ipv6Addr) -> Option<[u8; 4]> {
+    match ip.octets() {
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => Some([a, b, c, d]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_addresses() {
+        let mapped: SocketAddr = "[::ffff:127.0.0.1]:8080".parse().unwrap();
+        assert_eq!("127.0.0.1:8080", canonical(mapped).to_string());
+        let loopback: SocketAddr = "[::1]:8080".parse().unwrap();
+        assert_eq!(loopback, canonical(loopback));
+    }
+
+    #[tokio::test]
+    async fn sending_between_families() {
+        let any = match Udp::bind("[::]:0".parse().unwrap()) {
+            Ok(socket) => socket,
+            // IPv6 is disabled, nothing to test.
+            Err(_) => return,
+        };
+        let ipv4 = Udp::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let port = any.local_addr().unwrap().port();
+        let target = SocketAddr::from(([127, 0, 0, 1], port));
+        if ipv4.send_to(b"hello", target).await.is_err() {
+            return;
+        }
+
+        let (datagram, peer) =
+            match tokio::time::timeout(std::time::Duration::from_secs(1), any.recv_from()).await {
+                Ok(received) => received.unwrap(),
+                // The system doesn't allow dual-stack sockets.
+                Err(_) => return,
+            };
+        assert_eq!(b"hello".to_vec(), datagram);
+        assert_eq!(ipv4.local_addr().unwrap(), peer);
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    fs, io,
+    net::SocketAddr,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use rand::Rng;
+use socket2::SockRef;
+use tokio::net::UnixDatagram;
+
+use super::{link::Link, udp::canonical};
+
+/// The largest datagram, and the send buffer that is asked for so that it fits.
+const MAX_UNIX_DATAGRAM_SIZE: usize = 1 << 16;
+/// The owner and the group of the socket can send to it, nobody else.
+const SOCKET_MODE: u32 = 0o660;
+/// Where a node that binds to port 0 picks its port from.
+const EPHEMERAL_PORTS: std::ops::Range<u16> = 49152..65535;
+
+/// Datagrams over Unix sockets in a directory, for nodes on the same host. Nodes keep their
+/// addresses, which only name their socket in the directory, like `[::1]:8080`. Who can talk to
+/// a node is up to the permissions of its socket and of the directory, no port is opened.
+#[derive(Debug)]
+pub(crate) struct Unix {
+    socket: UnixDatagram,
+    directory: PathBuf,
+    local: SocketAddr,
+    path: PathBuf,
+}
+
+impl Unix {
+    pub(crate) fn bind(directory: &Path, address: SocketAddr) -> io::Result<Unix> {
+        let mut local = canonical(address);
+        let (socket, path) = if local.port() != 0 {
+            let path = socket_path(directory, local);
+            remove_stale(&path)?;
+            (UnixDatagram::bind(&path)?, path)
+        } else {
+            // Like an ephemeral port, a socket name that isn't taken yet.
+            loop {
+                local.set_port(rand::thread_rng().gen_range(EPHEMERAL_PORTS));
+                let path = socket_path(directory, local);
+                match UnixDatagram::bind(&path) {
+                    Ok(socket) => break (socket, path),
+                    Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        fs::set_permissions(&path, fs::Permissions::from_mode(SOCKET_MODE))?;
+        // The default is too small for a whole datagram on some systems.
+        let _ = SockRef::from(&socket).set_send_buffer_size(2 * MAX_UNIX_DATAGRAM_SIZE);
+
+        Ok(Unix {
+            socket,
+            directory: directory.to_path_buf(),
+            local,
+            path,
+        })
+    }
+}
+
+impl Drop for Unix {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Link for Unix {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn max_datagram(&self) -> usize {
+        MAX_UNIX_DATAGRAM_SIZE
+    }
+
+    fn send_to<'a>(
+        &'a self,
+        datagram: &'a [u8],
+        peer: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let path = socket_path(&self.directory, peer);
+        async move { self.socket.send_to(datagram, path).await.map(|_| ()) }.boxed()
+    }
+
+    fn recv_from(&self) -> BoxFuture<'_, io::Result<(Vec<u8>, SocketAddr)>> {
+        async move {
+            // One extra byte to tell when a datagram is too large.
+            let mut buf = vec![0; MAX_UNIX_DATAGRAM_SIZE + 1];
+            let (size, peer) = self.socket.recv_from(&mut buf).await?;
+            buf.truncate(size);
+
+            let peer = peer
+                .as_pathname()
+                .and_then(|path| path.file_name())
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse().ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("a datagram from {:?}, which isn't a node", peer),
+                    )
+                })?;
+            Ok((buf, canonical(peer)))
+        }
+        .boxed()
+    }
+}
+
+/// The socket of the node with the address.
+fn socket_path(directory: &Path, address: SocketAddr) -> PathBuf {
+    directory.join(canonical(address).to_string())
+}
+
+/// Remove the socket that a node which didn't shut down cleanly left behind. A socket that a
+/// node still listens on, or anything that isn't a socket, is left alone, and binding fails on it.
+fn remove_stale(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            match std::os::unix::net::UnixDatagram::unbound()?.connect(path) {
+                Ok(()) => Ok(()),
+                Err(_) => fs::remove_file(path),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("chat-unix-{}-{}", name, rand::random::<u32>()));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[tokio::test]
+    async fn sending_datagrams() {
+        let directory = directory("sending");
+        let server = Unix::bind(&directory, "[::1]:8080".parse().unwrap()).unwrap();
+        let client = Unix::bind(&directory, "[::1]:0".parse().unwrap()).unwrap();
+        let server_address = server.local_addr().unwrap();
+        let client_address = client.local_addr().unwrap();
+        assert_ne!(0, client_address.port());
+
+        let large = vec![7; 50_000];
+        client.send_to(&large, server_address).await.unwrap();
+        assert_eq!((large, client_address), server.recv_from().await.unwrap());
+        server.send_to(b"reply", client_address).await.unwrap();
+        assert_eq!(
+            (b"reply".to_vec(), server_address),
+            client.recv_from().await.unwrap()
+        );
+
+        let mode = fs::metadata(directory.join("[::1]:8080"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(SOCKET_MODE, mode & 0o777);
+
+        drop(server);
+        drop(client);
+        assert_eq!(0, fs::read_dir(&directory).unwrap().count());
+        fs::remove_dir(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replacing_stale_sockets() {
+        let directory = directory("stale");
+        let address = "127.0.0.1:8080".parse().unwrap();
+        // Closing a socket leaves its file behind.
+        drop(std::os::unix::net::UnixDatagram::bind(directory.join("127.0.0.1:8080")).unwrap());
+
+        let unix = Unix::bind(&directory, address).unwrap();
+        assert_eq!(address, unix.local_addr().unwrap());
+        // A socket in use isn't stale.
+        assert!(Unix::bind(&directory, address).is_err());
+        drop(unix);
+        fs::remove_dir(&directory).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    io::{self, BufRead, Write},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::Message;
+
+/// The formats that the history can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON encoded [Message] per line. This is the only format that can be imported.
+    JsonLines,
+    /// A plain text transcript.
+    Text,
+    /// A standalone HTML page.
+    Html,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(Format::JsonLines),
+            "text" => Ok(Format::Text),
+            "html" => Ok(Format::Html),
+            _ => Err(anyhow!(
+                "{} is not a format, use one of: jsonl, text, html",
+                s
+            )),
+        }
+    }
+}
+
+/// Write the messages, oldest first, in the given format.
+pub fn export<'a, I, W>(messages: I, format: Format, mut out: W) -> io::Result<()>
+where
+    I: IntoIterator<Item = &'a Message>,
+    W: Write,
+{
+    if format == Format::Html {
+        writeln!(
+            out,
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Chat History</title></head>\n<body>\n<ul>"
+        )?;
+    }
+
+    for message in messages {
+        match format {
+            Format::JsonLines => {
+                serde_json::to_writer(&mut out, message)?;
+                writeln!(out)?;
+            }
+            Format::Text => {
+                writeln!(
+                    out,
+                    "{} - {}: {}{}",
+                    message.sent_time.format("%Y-%m-%d %H:%M:%S UTC"),
+                    message.sender,
+                    message.text,
+                    tags(message)
+                )?;
+                for (i, option) in poll_options(message).enumerate() {
+                    writeln!(out, "    {}. {} ({})", i + 1, option.0, option.1)?;
+                }
+            }
+            Format::Html => {
+                write!(
+                    out,
+                    "<li><time>{}</time> <b>{}</b>: {}{}",
+                    message.sent_time.format("%Y-%m-%d %H:%M:%S UTC"),
+                    escape(&message.sender),
+                    escape(&message.text).replace('\n', "<br>"),
+                    escape(&tags(message))
+                )?;
+                let mut options = poll_options(message).peekable();
+                if options.peek().is_some() {
+                    write!(out, "<ol>")?;
+                    for (text, votes) in options {
+                        write!(out, "<li>{} ({})</li>", escape(text), votes)?;
+                    }
+                    write!(out, "</ol>")?;
+                }
+                writeln!(out, "</li>")?;
+            }
+        }
+    }
+
+    if format == Format::Html {
+        writeln!(out, "</ul>\n</body>\n</html>")?;
+    }
+
+    Ok(())
+}
+
+/// Read messages written with [Format::JsonLines]. Blank lines are skipped.
+pub fn import<R>(input: R) -> Result<Vec<Message>>
+where
+    R: BufRead,
+{
+    let mut out = vec![];
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        out.push(
+            serde_json::from_str(&line)
+                .with_context(|| format!("Invalid message on line {}", i + 1))?,
+        );
+    }
+
+    Ok(out)
+}
+
+/// Describe the poll and attachment of a message, if it has them.
+fn tags(message: &Message) -> String {
+    let mut out = String::new();
+    if let Some(attachment) = &message.attachment {
+        out += &format!(" [file: {}, {} bytes]", attachment.name, attachment.size);
+    }
+    if let Some(poll) = &message.poll {
+        out += if poll.closed {
+            " [closed poll]"
+        } else {
+            " [poll]"
+        };
+    }
+    out
+}
+
+/// The text and the number of votes of each of the message's poll options.
+fn poll_options(message: &Message) -> impl Iterator<Item = (&str, usize)> {
+    message
+        .poll
+        .iter()
+        .flat_map(|poll| poll.options.iter())
+        .map(|option| (option.text.as_str(), option.voters.len()))
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::new("alice".to_string(), "hi <bob>".to_string()),
+            Message::new("bob".to_string(), "hello & welcome".to_string()),
+        ]
+    }
+
+    #[test]
+    fn json_lines_round_trip() {
+        let messages = messages();
+
+        let mut out = vec![];
+        export(&messages, Format::JsonLines, &mut out).unwrap();
+
+        assert_eq!(messages, import(&out[..]).unwrap());
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        let mut out = vec![];
+        export(&messages(), Format::Html, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("<b>alice</b>: hi &lt;bob&gt;</li>"));
+        assert!(out.contains("hello &amp; welcome"));
+    }
+
+    #[test]
+    fn invalid_line() {
+        let err = import(&b"{}\n"[..]).unwrap_err();
+        assert_eq!("Invalid message on line 1", err.to_string());
+    }
+}
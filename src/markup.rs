@@ -0,0 +1,208 @@
+use std::fmt;
+
+use tui::{
+    style::{Color, Modifier, Style},
+    text::Span,
+};
+
+/// How a piece of a message should be displayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Plain,
+    /// `**bold**`
+    Bold,
+    /// `*italic*`
+    Italic,
+    /// `` `code` ``
+    Code,
+    /// A line inside of a ```` ``` ```` fenced block.
+    CodeBlock,
+    /// Any word starting with `http://` or `https://`.
+    Link,
+}
+
+impl Format {
+    pub fn style(self) -> Style {
+        match self {
+            Format::Plain => Style::default(),
+            Format::Bold => Style::default().add_modifier(Modifier::BOLD),
+            Format::Italic => Style::default().add_modifier(Modifier::ITALIC),
+            Format::Code | Format::CodeBlock => Style::default().fg(Color::Yellow),
+            Format::Link => Style::default()
+                .fg(Color::Blue)
+                .add_modifier(Modifier::UNDERLINED),
+        }
+    }
+}
+
+/// A run of text with a single format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub text: String,
+    pub format: Format,
+}
+
+impl Segment {
+    fn new(text: &str, format: Format) -> Segment {
+        Segment {
+            text: text.to_string(),
+            format,
+        }
+    }
+}
+
+impl<'a> From<Segment> for Span<'a> {
+    fn from(val: Segment) -> Self {
+        Span::styled(val.text, val.format.style())
+    }
+}
+
+/// One line of formatted text.
+pub type Line = Vec<Segment>;
+
+/// The text has markup that was opened but never closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unclosed(pub Format);
+
+impl fmt::Display for Unclosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unclosed {:?} markup", self.0)
+    }
+}
+
+impl std::error::Error for Unclosed {}
+
+/// Split the text into lines of formatted segments.
+pub fn parse(text: &str) -> Result<Vec<Line>, Unclosed> {
+    // Every other part is inside of a code block.
+    let parts: Vec<_> = text.split("```").collect();
+    if parts.len() % 2 == 0 {
+        return Err(Unclosed(Format::CodeBlock));
+    }
+
+    let mut lines = vec![vec![]];
+    for (i, part) in parts.into_iter().enumerate() {
+        if i % 2 == 1 {
+            // Code blocks always get their own lines.
+            for line in part.trim_matches('\n').lines() {
+                lines.push(vec![Segment::new(line, Format::CodeBlock)]);
+            }
+            lines.push(vec![]);
+        } else {
+            for (j, line) in part.split('\n').enumerate() {
+                if j > 0 {
+                    lines.push(vec![]);
+                }
+                parse_line(line, lines.last_mut().unwrap())?;
+            }
+        }
+    }
+
+    // Don't leave a blank line behind a trailing code block.
+    if lines.len() > 1 && matches!(lines.last(), Some(line) if line.is_empty()) {
+        lines.pop();
+    }
+
+    Ok(lines)
+}
+
+/// Parse the text, showing it exactly as written if the markup is invalid.
+pub fn parse_or_literal(text: &str) -> Vec<Line> {
+    parse(text).unwrap_or_else(|_| {
+        text.split('\n')
+            .map(|line| vec![Segment::new(line, Format::Plain)])
+            .collect()
+    })
+}
+
+fn parse_line(line: &str, out: &mut Line) -> Result<(), Unclosed> {
+    let mut plain = String::new();
+    let mut rest = line;
+
+    while let Some(c) = rest.chars().next() {
+        let (delimiter, format) = if rest.starts_with("**") {
+            ("**", Format::Bold)
+        } else if c == '*' {
+            ("*", Format::Italic)
+        } else if c == '`' {
+            ("`", Format::Code)
+        } else if rest.starts_with("http://") || rest.starts_with("https://") {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            flush(&mut plain, out);
+            out.push(Segment::new(&rest[..end], Format::Link));
+            rest = &rest[end..];
+            continue;
+        } else {
+            plain.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+
+        let body = &rest[delimiter.len()..];
+        let end = body
+            .find(delimiter)
+            .filter(|&end| end > 0)
+            .ok_or(Unclosed(format))?;
+
+        flush(&mut plain, out);
+        out.push(Segment::new(&body[..end], format));
+        rest = &body[end + delimiter.len()..];
+    }
+
+    flush(&mut plain, out);
+
+    Ok(())
+}
+
+fn flush(plain: &mut String, out: &mut Line) {
+    if !plain.is_empty() {
+        out.push(Segment {
+            text: std::mem::take(plain),
+            format: Format::Plain,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_formats() {
+        assert_eq!(
+            Ok(vec![vec![
+                Segment::new("a ", Format::Plain),
+                Segment::new("bold", Format::Bold),
+                Segment::new(" ", Format::Plain),
+                Segment::new("italic", Format::Italic),
+                Segment::new(" ", Format::Plain),
+                Segment::new("code", Format::Code),
+                Segment::new(" ", Format::Plain),
+                Segment::new("https://example.com", Format::Link),
+            ]]),
+            parse("a **bold** *italic* `code` https://example.com")
+        );
+    }
+
+    #[test]
+    fn code_block() {
+        assert_eq!(
+            Ok(vec![
+                vec![Segment::new("look:", Format::Plain)],
+                vec![Segment::new("fn main() {}", Format::CodeBlock)],
+                vec![Segment::new("x *", Format::CodeBlock)],
+            ]),
+            parse("look:```\nfn main() {}\nx *\n```")
+        );
+    }
+
+    #[test]
+    fn unclosed_markup_is_literal() {
+        assert_eq!(Err(Unclosed(Format::Italic)), parse("5 * 3 = 15"));
+        assert_eq!(Err(Unclosed(Format::CodeBlock)), parse("```oops"));
+        assert_eq!(
+            vec![vec![Segment::new("**not bold", Format::Plain)]],
+            parse_or_literal("**not bold")
+        );
+    }
+}
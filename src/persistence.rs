@@ -0,0 +1,974 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::VecDeque,
+    convert::TryInto,
+    fmt,
+    fs::{self, File, OpenOptions},
+    future::Future,
+    hash::{Hash, Hasher},
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use chrono::{DateTime, Utc};
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+
+use crate::{ChatApp, ChatCommand, Message};
+
+/// The name of the log inside of the data directory.
+pub const LOG_FILE: &str = "chat.log";
+/// Snapshots are named with this prefix followed by the sequence number of the last entry in them.
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+/// The number of snapshots kept in the data directory. Older ones are kept in case the newest
+/// one is damaged, the log is only compacted up to the oldest one.
+const KEPT_SNAPSHOTS: usize = 2;
+/// Every record starts with the length and the CRC-32 of its payload.
+const HEADER_SIZE: usize = 8;
+
+/// A command that changed the chat and when it was applied.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    pub command: ChatCommand,
+}
+
+/// Durably records the commands that changed a [ChatApp], so that it can be rebuilt with
+/// [ChatApp::replay].
+pub trait Journal: Send {
+    /// Record the entry. The change must not be acknowledged if this fails, it may be lost.
+    fn record(&mut self, entry: Entry) -> io::Result<()>;
+
+    /// Called with the chat after every command, once its changes are recorded.
+    fn checkpoint(&mut self, _chat: &ChatApp) {}
+}
+
+/// The journal that a [ChatApp] writes to, if any. Journals are never compared so that
+/// [ChatApp] can still be compared and hashed.
+#[derive(Clone, Default)]
+pub(crate) struct SharedJournal(pub(crate) Option<Arc<Mutex<dyn Journal>>>);
+
+impl SharedJournal {
+    pub(crate) fn record(&self, entry: Entry) -> io::Result<()> {
+        match &self.0 {
+            Some(journal) => journal.lock().unwrap().record(entry),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn checkpoint(&self, chat: &ChatApp) {
+        if let Some(journal) = &self.0 {
+            journal.lock().unwrap().checkpoint(chat);
+        }
+    }
+}
+
+impl fmt::Debug for SharedJournal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedJournal({})", self.0.is_some())
+    }
+}
+
+impl PartialEq for SharedJournal {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for SharedJournal {}
+
+impl PartialOrd for SharedJournal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SharedJournal {
+    fn cmp(&self, _other: &Self) -> Ordering {
+        Ordering::Equal
+    }
+}
+
+impl Hash for SharedJournal {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+/// An entry in the log, numbered so that the entries already in a snapshot can be skipped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Record {
+    seq: u64,
+    entry: Entry,
+}
+
+/// The chat's data directory: snapshots of the chat and a log of the entries since them.
+/// Snapshots are taken of the chat that journals to the storage, see [Storage::request_snapshot].
+pub struct Storage {
+    log: Wal,
+    /// The sequence number of the last entry.
+    seq: u64,
+    /// Shared with the thread that writes them.
+    snapshots: Arc<Mutex<Snapshots>>,
+    /// Whether to take a snapshot at the next checkpoint.
+    snapshot_requested: bool,
+    /// The sequence number of the last snapshot that was handed to the background thread.
+    queued: Option<u64>,
+    /// Writes the snapshots on a thread of their own, so that requests don't wait for them.
+    background: Option<(mpsc::Sender<(u64, ChatApp)>, JoinHandle<()>)>,
+}
+
+/// The snapshots in the data directory.
+struct Snapshots {
+    dir: PathBuf,
+    /// The sequence numbers of the snapshots, newest first.
+    seqs: Vec<u64>,
+    /// The log that the snapshots stand in for the start of.
+    log: Arc<Syncer>,
+}
+
+impl Storage {
+    /// Open the data directory, creating it if needed, and rebuild the chat from the newest
+    /// valid snapshot and the entries in the log after it.
+    pub fn open(dir: &Path) -> io::Result<(Storage, ChatApp)> {
+        fs::create_dir_all(dir)?;
+
+        let (seq, mut chat, seqs) = load_snapshot(dir)?;
+        let (log, records) = Wal::open(&dir.join(LOG_FILE))?;
+        let seq = replay(&mut chat, seq, records);
+
+        let snapshots = Arc::new(Mutex::new(Snapshots {
+            dir: dir.to_path_buf(),
+            seqs,
+            log: Arc::clone(&log.syncer),
+        }));
+        let (sender, receiver) = mpsc::channel::<(u64, ChatApp)>();
+        let writer = Arc::clone(&snapshots);
+        let thread = thread::Builder::new()
+            .name("snapshots".to_string())
+            .spawn(move || {
+                for (seq, chat) in receiver {
+                    if let Err(e) = writer.lock().unwrap().write(seq, &chat) {
+                        eprintln!("Failed to write a snapshot: {:?}", e);
+                    }
+                }
+            })?;
+
+        let mut storage = Storage {
+            log,
+            seq,
+            snapshots,
+            snapshot_requested: false,
+            queued: None,
+            background: Some((sender, thread)),
+        };
+
+        // Save a new chat right away so that it keeps its update id epoch across restarts.
+        if storage.seq == 0 && storage.snapshots.lock().unwrap().seqs.is_empty() {
+            storage.snapshot(&chat)?;
+        }
+
+        Ok((storage, chat))
+    }
+
+    /// Rebuild the chat from the data directory like [Storage::open], but without changing
+    /// anything in the directory, like for an export while the server is running.
+    pub fn load(dir: &Path) -> io::Result<ChatApp> {
+        let (seq, mut chat, _) = load_snapshot(dir)?;
+        let data = match fs::read(dir.join(LOG_FILE)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        replay(&mut chat, seq, decode_records(&data)?.0);
+        Ok(chat)
+    }
+
+    /// Create a new data directory that starts with the given chat. Fails if the directory
+    /// already has a chat in it.
+    pub fn create(dir: &Path, chat: ChatApp) -> io::Result<()> {
+        let (mut storage, _) = Storage::open(dir)?;
+
+        if storage.seq != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already has a chat in it", dir.display()),
+            ));
+        }
+
+        // The snapshot is numbered after the one entry it stands in for.
+        storage.seq = 1;
+        storage.snapshot(&chat)
+    }
+
+    /// Take a snapshot of the chat the next time it runs a command.
+    pub fn request_snapshot(&mut self) {
+        self.snapshot_requested = true;
+    }
+
+    /// Waits for the entries recorded so far to reach the disk.
+    pub fn synced(&self) -> Synced {
+        Synced(Arc::clone(&self.log.syncer))
+    }
+
+    /// Write a snapshot of the chat right away, see [Snapshots::write]. The chat has to be the
+    /// one that every entry so far was recorded from.
+    fn snapshot(&mut self, chat: &ChatApp) -> io::Result<()> {
+        self.snapshots.lock().unwrap().write(self.seq, chat)
+    }
+}
+
+impl Drop for Storage {
+    /// Finish the snapshots that were handed to the background thread.
+    fn drop(&mut self) {
+        if let Some((sender, thread)) = self.background.take() {
+            drop(sender);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Snapshots {
+    /// Write a snapshot of the chat at the sequence number if it changed since the last one, then
+    /// drop the entries that the kept snapshots cover from the log.
+    fn write(&mut self, seq: u64, chat: &ChatApp) -> io::Result<()> {
+        if self.seqs.first() == Some(&seq) {
+            return Ok(());
+        }
+
+        // Write the snapshot to the side, so that a crash never leaves a partial snapshot.
+        let path = snapshot_path(&self.dir, seq);
+        let temp = path.with_extension("tmp");
+        let snapshot = bincode::serialize(&(seq, chat)).expect("Failed to serialize the snapshot");
+        let mut file = File::create(&temp)?;
+        file.write_all(&crc32fast::hash(&snapshot).to_le_bytes())?;
+        file.write_all(&snapshot)?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)?;
+        sync_dir(&self.dir)?;
+
+        self.seqs.insert(0, seq);
+        for old in self.seqs.split_off(KEPT_SNAPSHOTS.min(self.seqs.len())) {
+            fs::remove_file(snapshot_path(&self.dir, old))?;
+        }
+
+        self.log
+            .compact(&self.dir.join(LOG_FILE), *self.seqs.last().unwrap())
+    }
+}
+
+impl Journal for Storage {
+    fn record(&mut self, entry: Entry) -> io::Result<()> {
+        let record = Record {
+            seq: self.seq + 1,
+            entry,
+        };
+        self.log.append(&record)?;
+
+        self.seq = record.seq;
+        Ok(())
+    }
+
+    /// Hand a copy of the chat to the background thread to write, if a snapshot was requested and
+    /// the chat changed since the last one.
+    fn checkpoint(&mut self, chat: &ChatApp) {
+        if !mem::take(&mut self.snapshot_requested) || self.queued == Some(self.seq) {
+            return;
+        }
+        if let Some((sender, _)) = &self.background {
+            // Without the journal, which is this storage, so the copy doesn't keep it open.
+            let chat = ChatApp {
+                journal: SharedJournal::default(),
+                ..chat.clone()
+            };
+            if sender.send((self.seq, chat)).is_ok() {
+                self.queued = Some(self.seq);
+            }
+        }
+    }
+}
+
+/// A handle to wait for the log to reach the disk, see [crate::context::Ctx::set_synced].
+#[derive(Clone)]
+pub struct Synced(Arc<Syncer>);
+
+impl Synced {
+    /// Wait until the entries recorded so far have reached the disk. Fails if syncing the log
+    /// failed, then they may be lost.
+    pub fn wait(&self) -> impl Future<Output = io::Result<()>> {
+        let (sender, receiver) = oneshot::channel();
+        let mut state = self.0.state.lock().unwrap();
+        let written = state.written;
+        state.waiting.push((written, sender));
+        state.wake_waiting();
+
+        async move {
+            receiver
+                .await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "the log is closed")))
+        }
+    }
+}
+
+/// Load the newest valid snapshot in the directory. Returns its sequence number, the chat, and
+/// the sequence numbers of it and the older snapshots, newest first.
+fn load_snapshot(dir: &Path) -> io::Result<(u64, ChatApp, Vec<u64>)> {
+    let mut snapshots = list_snapshots(dir)?;
+    for (i, &snapshot) in snapshots.iter().enumerate() {
+        let path = snapshot_path(dir, snapshot);
+        match read_snapshot(&path) {
+            Ok(chat) => {
+                // Newer snapshots that failed to load are useless.
+                snapshots.drain(..i);
+                return Ok((snapshot, chat, snapshots));
+            }
+            Err(e) => eprintln!("Skipping the snapshot {}: {}", path.display(), e),
+        }
+    }
+
+    // The log was compacted up to the snapshots, so starting over would lose the chat.
+    if !snapshots.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("every snapshot in {} is damaged", dir.display()),
+        ));
+    }
+    Ok((0, ChatApp::new(), snapshots))
+}
+
+/// Replay the records after the sequence number into the chat. Returns the sequence number of
+/// the last one.
+fn replay(chat: &mut ChatApp, mut seq: u64, records: Vec<Record>) -> u64 {
+    for record in records {
+        if record.seq > seq {
+            seq = record.seq;
+            chat.replay(record.entry);
+        }
+    }
+    seq
+}
+
+fn snapshot_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{}{:020}", SNAPSHOT_PREFIX, seq))
+}
+
+/// Get the sequence numbers of the snapshots in the directory, newest first.
+fn list_snapshots(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut out = vec![];
+    for file in fs::read_dir(dir)? {
+        let name = file?.file_name();
+        if let Some(seq) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(SNAPSHOT_PREFIX))
+            .and_then(|seq| seq.parse().ok())
+        {
+            out.push(seq);
+        }
+    }
+
+    out.sort_by_key(|&seq| Reverse(seq));
+    Ok(out)
+}
+
+fn read_snapshot(path: &Path) -> io::Result<ChatApp> {
+    let data = fs::read(path)?;
+
+    if data.len() < 4 || crc32fast::hash(&data[4..]).to_le_bytes() != data[..4] {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bad checksum"));
+    }
+
+    let (_, chat): (u64, ChatApp) = bincode::deserialize(&data[4..])
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(chat)
+}
+
+/// Make sure that files created or renamed in the directory survive a crash.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    if cfg!(unix) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// The posts that a client sent but that the server hasn't answered yet, kept in a file. A client
+/// that restarts sends them again with the same ids, so the server still only posts them once.
+pub struct Outbox {
+    path: PathBuf,
+    /// Oldest first.
+    pending: VecDeque<Message>,
+}
+
+impl Outbox {
+    /// Open the outbox in the file, or an empty one if the file doesn't exist yet.
+    pub fn open(path: &Path) -> io::Result<Outbox> {
+        let pending = match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => VecDeque::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Outbox {
+            path: path.to_path_buf(),
+            pending,
+        })
+    }
+
+    /// Save a post before it is sent. It needs an id for the server to recognize it by.
+    pub fn push(&mut self, post: Message) -> io::Result<()> {
+        assert!(post.id.is_some(), "Posts in the outbox need an id");
+        self.pending.push_back(post);
+        self.save()
+    }
+
+    /// The oldest post that wasn't answered.
+    pub fn front(&self) -> Option<&Message> {
+        self.pending.front()
+    }
+
+    /// Forget the post with the id once the server answered it.
+    pub fn confirm(&mut self, id: u64) -> io::Result<()> {
+        self.pending.retain(|post| post.id != Some(id));
+        self.save()
+    }
+
+    /// Replace the file, so that a crash leaves either the old or the new outbox.
+    fn save(&self) -> io::Result<()> {
+        let temp = self.path.with_extension("tmp");
+        let mut file = File::create(&temp)?;
+        serde_json::to_writer(&mut file, &self.pending)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)?;
+        match self.path.parent() {
+            Some(dir) if dir != Path::new("") => sync_dir(dir),
+            _ => sync_dir(Path::new(".")),
+        }
+    }
+}
+
+/// An append only log of [Record]s. Records are synced to disk on their own thread, and the
+/// responses that confirm them wait for it with [Synced].
+struct Wal {
+    syncer: Arc<Syncer>,
+}
+
+/// Syncs the log on a thread of its own, so that recording an entry doesn't wait for the disk.
+struct Syncer {
+    state: Mutex<SyncState>,
+    wake: Condvar,
+}
+
+struct SyncState {
+    /// The log file that is written to, replaced when the log is compacted.
+    file: Arc<File>,
+    /// How many records were written, and how many of them are known to be on the disk.
+    written: u64,
+    synced: u64,
+    /// Why syncing, or cutting off a write that failed, failed. Nothing more is written after that.
+    error: Option<String>,
+    /// The waiters for the records up to the count to be synced.
+    waiting: Vec<(u64, oneshot::Sender<io::Result<()>>)>,
+    closed: bool,
+}
+
+impl SyncState {
+    /// Answer the waiters whose records are synced, or that never will be.
+    fn wake_waiting(&mut self) {
+        for (written, sender) in mem::take(&mut self.waiting) {
+            if let Some(e) = &self.error {
+                let _ = sender.send(Err(io::Error::new(io::ErrorKind::Other, e.clone())));
+            } else if written <= self.synced {
+                let _ = sender.send(Ok(()));
+            } else {
+                self.waiting.push((written, sender));
+            }
+        }
+    }
+}
+
+impl Syncer {
+    /// Rewrite the log at the path without the records up to and including the sequence number.
+    /// Records are still appended while the older ones are copied, and only wait for the few
+    /// that came in meanwhile.
+    fn compact(&self, path: &Path, seq: u64) -> io::Result<()> {
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+        let (records, read) = decode_records(&data)?;
+        let kept: Vec<u8> = records
+            .iter()
+            .filter(|record| record.seq > seq)
+            .flat_map(encode_record)
+            .collect();
+
+        let temp = path.with_extension("tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(&kept)?;
+        file.sync_all()?;
+
+        // Nothing is appended while the rest is copied and the new log takes the old one's place.
+        let mut state = self.state.lock().unwrap();
+        let mut appended = vec![];
+        let mut log = File::open(path)?;
+        log.seek(SeekFrom::Start(read as u64))?;
+        log.read_to_end(&mut appended)?;
+        file.write_all(&appended)?;
+        file.sync_all()?;
+        fs::rename(&temp, path)?;
+        if let Some(dir) = path.parent() {
+            sync_dir(dir)?;
+        }
+
+        // The new log was synced as a whole.
+        state.file = Arc::new(OpenOptions::new().append(true).open(path)?);
+        state.synced = state.written;
+        state.wake_waiting();
+        Ok(())
+    }
+
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        while !state.closed {
+            if state.synced == state.written || state.error.is_some() {
+                state = self.wake.wait(state).unwrap();
+                continue;
+            }
+
+            // Everything written before this point is synced by it, writes can go on meanwhile.
+            let (written, file) = (state.written, Arc::clone(&state.file));
+            drop(state);
+            let result = file.sync_data();
+            state = self.state.lock().unwrap();
+            match result {
+                Ok(()) => state.synced = state.synced.max(written),
+                Err(e) => state.error = Some(e.to_string()),
+            }
+            state.wake_waiting();
+        }
+    }
+}
+
+impl Wal {
+    /// Open the log, creating it if needed, and read its records. A torn record at the end of the
+    /// log, left by a crash in the middle of a write, is cut off. A damaged record anywhere else
+    /// is an error, so that the records after it aren't lost.
+    fn open(path: &Path) -> io::Result<(Wal, Vec<Record>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+
+        let (records, valid) = decode_records(&data)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        if valid < data.len() {
+            eprintln!(
+                "Dropping {} bytes of a torn record at the end of {}",
+                data.len() - valid,
+                path.display()
+            );
+            file.set_len(valid as u64)?;
+            file.sync_all()?;
+        }
+
+        let syncer = Arc::new(Syncer {
+            state: Mutex::new(SyncState {
+                file: Arc::new(file),
+                written: 0,
+                synced: 0,
+                error: None,
+                waiting: vec![],
+                closed: false,
+            }),
+            wake: Condvar::new(),
+        });
+        let running = Arc::clone(&syncer);
+        thread::Builder::new()
+            .name("log-sync".to_string())
+            .spawn(move || running.run())?;
+
+        let log = Wal { syncer };
+
+        Ok((log, records))
+    }
+
+    /// Add a record to the end of the log. It reaches the disk later, see [Synced].
+    fn append(&mut self, record: &Record) -> io::Result<()> {
+        let mut state = self.syncer.state.lock().unwrap();
+        if let Some(e) = &state.error {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("the log can't be written anymore: {}", e),
+            ));
+        }
+
+        // A write that fails part of the way is cut back off, so that the next record doesn't go
+        // after a damaged one. If that fails too, nothing more is written.
+        let file = Arc::clone(&state.file);
+        let start = file.metadata()?.len();
+        if let Err(e) = (&*file).write_all(&encode_record(record)) {
+            if let Err(cut) = file.set_len(start) {
+                state.error = Some(format!(
+                    "{}, and cutting off the partial record failed: {}",
+                    e, cut
+                ));
+                state.wake_waiting();
+            }
+            return Err(e);
+        }
+        state.written += 1;
+        self.syncer.wake.notify_one();
+        Ok(())
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        self.syncer.state.lock().unwrap().closed = true;
+        self.syncer.wake.notify_one();
+    }
+}
+
+fn encode_record(record: &Record) -> Vec<u8> {
+    let payload = bincode::serialize(record).expect("Failed to serialize the record");
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + payload.len());
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    out.extend_from_slice(&payload);
+    out
+}
+
+/// Read the records in the data, up to a torn record at the end, one that is cut short or the
+/// last one that is damaged. Returns the records and the number of bytes that they take up. A
+/// damaged record that isn't the last one is an error, even when its length is what's damaged
+/// and it seems to run past the end.
+fn decode_records(data: &[u8]) -> io::Result<(Vec<Record>, usize)> {
+    let mut records = vec![];
+    let mut offset = 0;
+
+    while data.len() - offset >= HEADER_SIZE {
+        let header = &data[offset..offset + HEADER_SIZE];
+        let length = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let end = offset + HEADER_SIZE + length;
+
+        let record = data
+            .get(offset + HEADER_SIZE..end)
+            .and_then(|payload| decode_payload(payload, checksum));
+        match record {
+            Some(record) => records.push(record),
+            None if end >= data.len() && !has_record_after(data, offset) => break,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "the record at byte {} is damaged, and there are more after it",
+                        offset
+                    ),
+                ))
+            }
+        }
+
+        offset = end;
+    }
+
+    Ok((records, offset))
+}
+
+fn decode_payload(payload: &[u8], checksum: u32) -> Option<Record> {
+    if crc32fast::hash(payload) == checksum {
+        bincode::deserialize(payload).ok()
+    } else {
+        None
+    }
+}
+
+/// Whether a valid record starts anywhere after the offset. A torn record is the last thing
+/// written, so there can't be one after it.
+fn has_record_after(data: &[u8], offset: usize) -> bool {
+    (offset + 1..data.len().saturating_sub(HEADER_SIZE)).any(|start| {
+        let header = &data[start..start + HEADER_SIZE];
+        let length = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+        data.get(start + HEADER_SIZE..start + HEADER_SIZE + length)
+            .and_then(|payload| decode_payload(payload, checksum))
+            .is_some()
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::ops::Range;
+
+    use ds_libs::Application;
+
+    use super::*;
+    use crate::{ChatError, ChatResponse, UpdateId};
+
+    /// Get an empty directory for a test to keep its files in.
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chat-app-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Post the messages to a chat journaled to the storage.
+    fn post_messages(storage: &Arc<Mutex<Storage>>, chat: &mut ChatApp, range: Range<usize>) {
+        chat.set_journal(storage.clone());
+
+        for i in range {
+            assert_eq!(
+                ChatResponse::PostOk,
+                chat.process(ChatCommand::Post(Message::new(
+                    format!("sender {}", i),
+                    format!("message {}", i),
+                )))
+            );
+        }
+    }
+
+    fn open(dir: &Path) -> (Arc<Mutex<Storage>>, ChatApp) {
+        let (storage, chat) = Storage::open(dir).unwrap();
+        (Arc::new(Mutex::new(storage)), chat)
+    }
+
+    #[test]
+    fn replaying_the_log() {
+        let dir = test_dir("replaying-the-log");
+
+        let (storage, mut chat) = open(&dir);
+        post_messages(&storage, &mut chat, 0..3);
+        let (_, replayed) = open(&dir);
+
+        assert_eq!(chat, replayed);
+    }
+
+    #[test]
+    fn torn_final_record() {
+        let dir = test_dir("torn-final-record");
+
+        let (storage, mut chat) = open(&dir);
+        post_messages(&storage, &mut chat, 0..3);
+
+        // Simulate a crash part of the way through writing a record.
+        let path = dir.join(LOG_FILE);
+        let length = fs::metadata(&path).unwrap().len();
+        let record = encode_record(&Record {
+            seq: 4,
+            entry: Entry {
+                time: Utc::now(),
+                command: ChatCommand::GetLatest(UpdateId::default()),
+            },
+        });
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&record[..record.len() - 1])
+            .unwrap();
+
+        let (_, replayed) = open(&dir);
+        assert_eq!(chat, replayed);
+        assert_eq!(length, fs::metadata(&path).unwrap().len());
+    }
+
+    #[test]
+    fn damaged_record() {
+        let dir = test_dir("damaged-record");
+
+        let (storage, mut chat) = open(&dir);
+        post_messages(&storage, &mut chat, 0..3);
+        drop(storage);
+
+        // Flip a byte in the payload of the first record, then in its length, so that it seems
+        // to run past the end of the log.
+        let path = dir.join(LOG_FILE);
+        let original = fs::read(&path).unwrap();
+        for &byte in &[HEADER_SIZE, 2] {
+            let mut data = original.clone();
+            data[byte] ^= 0xff;
+            fs::write(&path, &data).unwrap();
+
+            assert!(Storage::open(&dir).is_err());
+            assert!(Storage::load(&dir).is_err());
+            assert_eq!(data, fs::read(&path).unwrap());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failing_writes() {
+        let dir = test_dir("failing-writes");
+
+        let (storage, mut chat) = open(&dir);
+        post_messages(&storage, &mut chat, 0..1);
+
+        // The disk fills up, and the partial record can't be cut off either.
+        let full = OpenOptions::new().append(true).open("/dev/full").unwrap();
+        storage
+            .lock()
+            .unwrap()
+            .log
+            .syncer
+            .state
+            .lock()
+            .unwrap()
+            .file = Arc::new(full);
+
+        let post = ChatCommand::Post(Message::new("sender".to_string(), "message".to_string()));
+        for _ in 0..2 {
+            assert!(matches!(
+                chat.process(post.clone()),
+                ChatResponse::Error(ChatError::NotSaved(_))
+            ));
+        }
+        let storage = storage.lock().unwrap();
+        assert!(storage.log.syncer.state.lock().unwrap().error.is_some());
+    }
+
+    #[test]
+    fn snapshots_compact_the_log() {
+        let dir = test_dir("snapshots-compact-the-log");
+
+        let (storage, mut chat) = open(&dir);
+        for batch in 0..3 {
+            post_messages(&storage, &mut chat, batch * 5..(batch + 1) * 5);
+            storage.lock().unwrap().snapshot(&chat).unwrap();
+        }
+        post_messages(&storage, &mut chat, 15..17);
+
+        // Only the entries after the older of the two kept snapshots are left.
+        assert_eq!(vec![15, 10], list_snapshots(&dir).unwrap());
+        let (_, records) = Wal::open(&dir.join(LOG_FILE)).unwrap();
+        assert_eq!(
+            (11..=17).collect::<Vec<_>>(),
+            records.iter().map(|r| r.seq).collect::<Vec<_>>()
+        );
+
+        let (_, replayed) = open(&dir);
+        assert_eq!(chat, replayed);
+    }
+
+    #[test]
+    fn waiting_for_the_disk() {
+        let dir = test_dir("waiting-for-the-disk");
+
+        let (storage, mut chat) = open(&dir);
+        let synced = storage.lock().unwrap().synced();
+        post_messages(&storage, &mut chat, 0..3);
+        futures::executor::block_on(synced.wait()).unwrap();
+        assert_eq!(
+            3,
+            storage
+                .lock()
+                .unwrap()
+                .log
+                .syncer
+                .state
+                .lock()
+                .unwrap()
+                .synced
+        );
+
+        // Snapshots are taken from the chat once they're requested, in the background, and the
+        // ones handed over are finished before the storage closes.
+        storage.lock().unwrap().request_snapshot();
+        assert_eq!(vec![0], list_snapshots(&dir).unwrap());
+        post_messages(&storage, &mut chat, 3..4);
+        futures::executor::block_on(synced.wait()).unwrap();
+        drop((storage, chat));
+        assert_eq!(vec![4, 0], list_snapshots(&dir).unwrap());
+
+        let (_, replayed) = open(&dir);
+        assert_eq!(4, replayed.history().len());
+    }
+
+    #[test]
+    fn damaged_snapshots() {
+        let dir = test_dir("damaged-snapshot");
+
+        let (storage, mut chat) = open(&dir);
+        for batch in 0..2 {
+            post_messages(&storage, &mut chat, batch * 5..(batch + 1) * 5);
+            storage.lock().unwrap().snapshot(&chat).unwrap();
+        }
+
+        // Fall back to the older snapshot and the log after it.
+        fs::write(snapshot_path(&dir, 10), b"garbage").unwrap();
+
+        let (_, replayed) = open(&dir);
+        assert_eq!(chat, replayed);
+    }
+
+    #[test]
+    fn loading_without_changes() {
+        let dir = test_dir("loading-without-changes");
+
+        let (storage, mut chat) = open(&dir);
+        post_messages(&storage, &mut chat, 0..3);
+        // A record that is still being written.
+        let path = dir.join(LOG_FILE);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[1, 2, 3])
+            .unwrap();
+        let length = fs::metadata(&path).unwrap().len();
+
+        assert_eq!(chat, Storage::load(&dir).unwrap());
+        assert_eq!(length, fs::metadata(&path).unwrap().len());
+        assert_eq!(vec![0], list_snapshots(&dir).unwrap());
+
+        let empty = test_dir("loading-an-empty-directory");
+        fs::create_dir_all(&empty).unwrap();
+        assert!(Storage::load(&empty).is_ok());
+        assert_eq!(0, fs::read_dir(&empty).unwrap().count());
+    }
+
+    #[test]
+    fn creating_from_a_chat() {
+        let dir = test_dir("creating-from-a-chat");
+
+        let chat = ChatApp::from_history(vec![Message::new(
+            "sender".to_string(),
+            "message".to_string(),
+        )]);
+        Storage::create(&dir, chat.clone()).unwrap();
+        assert!(Storage::create(&dir, chat.clone()).is_err());
+
+        let (storage, mut opened) = open(&dir);
+        assert_eq!(chat, opened);
+
+        // New entries go after the created chat.
+        post_messages(&storage, &mut opened, 0..1);
+        let (_, replayed) = open(&dir);
+        assert_eq!(opened, replayed);
+    }
+
+    #[test]
+    fn keeping_unanswered_posts() {
+        let dir = test_dir("keeping-unanswered-posts");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("outbox");
+        let post = |id| Message {
+            id: Some(id),
+            ..Message::new("sender".to_string(), format!("message {}", id))
+        };
+
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(None, outbox.front());
+        outbox.push(post(1)).unwrap();
+        outbox.push(post(2)).unwrap();
+
+        // The posts are still there after a restart, until they're answered.
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(Some(&post(1)), outbox.front());
+        outbox.confirm(1).unwrap();
+        assert_eq!(Some(&post(2)), Outbox::open(&path).unwrap().front());
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fmt,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::Instant,
+};
+
+use chrono::Utc;
+use rand::Rng;
+
+use crate::ChatError;
+
+/// A server side command that users run by posting `/<name> <args>`. The reply is posted in the
+/// history by the server.
+pub trait CommandHandler: Send + Sync {
+    /// The name the command is run with, without the leading `/`.
+    fn name(&self) -> &str;
+
+    /// A one line description shown by `/help`.
+    fn description(&self) -> &str;
+
+    /// Run the command for the sender. Returns the text to post, or why the command failed.
+    fn handle(&self, sender: &str, args: &str) -> Result<String, String>;
+}
+
+/// The registered [CommandHandler]s. Handlers are only compared by their names so that
+/// [crate::ChatApp] can still be compared and hashed.
+#[derive(Clone, Default)]
+pub struct Plugins(BTreeMap<String, Arc<dyn CommandHandler>>);
+
+impl Plugins {
+    /// The commands that every server has: `/roll`, `/time` and `/uptime`.
+    pub fn with_builtins() -> Plugins {
+        let mut out = Plugins::default();
+        out.register(Roll);
+        out.register(Time);
+        out.register(Uptime(Instant::now()));
+        out
+    }
+
+    /// Add a command, replacing any command with the same name.
+    pub fn register<H>(&mut self, handler: H)
+    where
+        H: CommandHandler + 'static,
+    {
+        self.0.insert(handler.name().to_string(), Arc::new(handler));
+    }
+
+    /// Run the command in the text, which should start with a `/`. `/help` lists the registered
+    /// commands.
+    pub fn dispatch(&self, sender: &str, text: &str) -> Result<String, ChatError> {
+        let mut split = text.trim_start_matches('/').splitn(2, ' ');
+        let name = split.next().unwrap_or("");
+        let args = split.next().unwrap_or("").trim();
+
+        if name == "help" {
+            return Ok(self
+                .0
+                .values()
+                .map(|h| format!("/{} - {}", h.name(), h.description()))
+                .collect::<Vec<_>>()
+                .join("\n"));
+        }
+
+        self.0
+            .get(name)
+            .ok_or_else(|| ChatError::UnknownCommand(name.to_string()))?
+            .handle(sender, args)
+            .map_err(ChatError::CommandFailed)
+    }
+
+    fn names(&self) -> impl Iterator<Item = &String> {
+        self.0.keys()
+    }
+}
+
+impl fmt::Debug for Plugins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.names()).finish()
+    }
+}
+
+impl PartialEq for Plugins {
+    fn eq(&self, other: &Self) -> bool {
+        self.names().eq(other.names())
+    }
+}
+
+impl Eq for Plugins {}
+
+impl PartialOrd for Plugins {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Plugins {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.names().cmp(other.names())
+    }
+}
+
+impl Hash for Plugins {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for name in self.names() {
+            name.hash(state);
+        }
+    }
+}
+
+/// `/roll [<count>d<sides>]`, roll some dice. Defaults to one six sided die.
+struct Roll;
+
+impl CommandHandler for Roll {
+    fn name(&self) -> &str {
+        "roll"
+    }
+
+    fn description(&self) -> &str {
+        "roll some dice, ex: /roll 2d6"
+    }
+
+    fn handle(&self, sender: &str, args: &str) -> Result<String, String> {
+        let dice = if args.is_empty() { "1d6" } else { args };
+
+        let mut split = dice.splitn(2, 'd');
+        let (count, sides) = match (split.next(), split.next()) {
+            (Some(count), Some(sides)) => (
+                if count.is_empty() {
+                    Ok(1)
+                } else {
+                    count.parse::<u32>()
+                },
+                sides.parse::<u32>(),
+            ),
+            _ => return Err(format!("{} is not a roll, ex: 2d6", dice)),
+        };
+        let (count, sides) = match (count, sides) {
+            (Ok(count @ 1..=100), Ok(sides @ 2..=1000)) => (count, sides),
+            _ => return Err("rolls must be 1-100 dice with 2-1000 sides".to_string()),
+        };
+
+        let mut rng = rand::thread_rng();
+        let rolls: Vec<_> = (0..count).map(|_| rng.gen_range(1..=sides)).collect();
+        let total: u32 = rolls.iter().sum();
+
+        Ok(format!(
+            "{} rolled {}d{}: {} = {}",
+            sender,
+            count,
+            sides,
+            rolls
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>()
+                .join(" + "),
+            total
+        ))
+    }
+}
+
+/// `/time`, the server's current time.
+struct Time;
+
+impl CommandHandler for Time {
+    fn name(&self) -> &str {
+        "time"
+    }
+
+    fn description(&self) -> &str {
+        "the server's current time"
+    }
+
+    fn handle(&self, _sender: &str, _args: &str) -> Result<String, String> {
+        Ok(format!(
+            "The server time is {}",
+            Utc::now().format("%Y-%m-%d %H:%M:%S UTC")
+        ))
+    }
+}
+
+/// `/uptime`, how long the server has been running.
+struct Uptime(Instant);
+
+impl CommandHandler for Uptime {
+    fn name(&self) -> &str {
+        "uptime"
+    }
+
+    fn description(&self) -> &str {
+        "how long the server has been running"
+    }
+
+    fn handle(&self, _sender: &str, _args: &str) -> Result<String, String> {
+        Ok(format!(
+            "The server has been up for {}",
+            format_duration(self.0.elapsed().as_secs())
+        ))
+    }
+}
+
+/// Format a number of seconds like `1d 2h 3m 4s`.
+pub(crate) fn format_duration(secs: u64) -> String {
+    let (days, hours, minutes, seconds) =
+        (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", seconds),
+        (0, 0, _) => format!("{}m {}s", minutes, seconds),
+        (0, _, _) => format!("{}h {}m {}s", hours, minutes, seconds),
+        _ => format!("{}d {}h {}m {}s", days, hours, minutes, seconds),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    impl CommandHandler for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn description(&self) -> &str {
+            "repeat the arguments"
+        }
+
+        fn handle(&self, _sender: &str, args: &str) -> Result<String, String> {
+            Ok(args.to_string())
+        }
+    }
+
+    #[test]
+    fn dispatching() {
+        let mut plugins = Plugins::default();
+        plugins.register(Echo);
+
+        assert_eq!(
+            Ok("hi there".to_string()),
+            plugins.dispatch("a", "/echo hi there")
+        );
+        assert_eq!(
+            Err(ChatError::UnknownCommand("nope".to_string())),
+            plugins.dispatch("a", "/nope")
+        );
+    }
+
+    #[test]
+    fn rolling() {
+        let reply = Roll.handle("a", "3d1000").unwrap();
+        assert!(reply.starts_with("a rolled 3d1000: "));
+
+        assert!(Roll.handle("a", "0d6").is_err());
+        assert!(Roll.handle("a", "d1").is_err());
+        assert!(Roll.handle("a", "six").is_err());
+    }
+
+    #[test]
+    fn formatting_durations() {
+        assert_eq!("5s", format_duration(5));
+        assert_eq!("1h 0m 5s", format_duration(3605));
+        assert_eq!("2d 0h 1m 0s", format_duration(2 * 86400 + 60));
+    }
+}
@@ -1,40 +1,161 @@
 use chat_application::{
-    context::{self, Ctx},
-    ChatApp,
+    cli::{parse_address, read_key_file, Args, NetworkOptions},
+    context::{self, Ctx, PrivateKey},
+    export,
+    persistence::Storage,
+    ChatApp, MAX_CHAT_MESSAGES,
 };
-use ds_libs::{address::Address, HandleMessage, InitializeNode};
-use futures::StreamExt;
+use ds_libs::{HandleMessage, InitializeNode};
+use futures::{select, FutureExt, StreamExt};
 use simple_server::user::Server;
-use std::{env, net::ToSocketAddrs};
+use std::{
+    env,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::interval;
+
+use anyhow::{anyhow, bail, Result};
 
-use anyhow::{anyhow, Result};
+/// How often a snapshot of the chat is written to the data directory.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// `chat-server export <data directory> <jsonl|text|html> [output file]`, write the history to the
+/// file or to stdout.
+fn export_history(args: &[String]) -> Result<()> {
+    if args.len() != 2 && args.len() != 3 {
+        bail!("Usage: chat-server export <data directory> <jsonl|text|html> [output file]");
+    }
 
-fn parse_address<Node>(s: &str) -> Result<Address<Node>> {
-    for ip_port in s.to_socket_addrs()? {
-        if let std::net::SocketAddr::V6(ip_port) = ip_port {
-            return Ok(Address::new((*ip_port.ip(), ip_port.port())))
+    let dir = Path::new(&args[0]);
+    if !dir.is_dir() {
+        bail!("{} is not a data directory", dir.display());
+    }
+    let format = args[1].parse()?;
+    let chat = Storage::load(dir)?;
+
+    match args.get(2) {
+        Some(path) => {
+            let mut out = BufWriter::new(File::create(path)?);
+            export::export(chat.history(), format, &mut out)?;
+            out.flush()?;
+        }
+        None => {
+            let stdout = io::stdout();
+            export::export(chat.history(), format, stdout.lock())?;
         }
     }
 
-    Err(anyhow!(
-        "Failed to produce an IPv6:port pair from the provided address: {}",
-        s
-    ))
+    Ok(())
+}
+
+/// `chat-server import <data directory> <jsonl file>`, create a new data directory from an
+/// exported history.
+fn import_history(args: &[String]) -> Result<()> {
+    if args.len() != 2 {
+        bail!("Usage: chat-server import <data directory> <jsonl file>");
+    }
+
+    let history = export::import(BufReader::new(File::open(&args[1])?))?;
+    let count = history.len().min(MAX_CHAT_MESSAGES);
+    Storage::create(Path::new(&args[0]), ChatApp::from_history(history))?;
+
+    println!("Imported {} messages into {}", count, args[0]);
+    Ok(())
+}
+
+/// `chat-server keygen <private key file> <public key file>`, generate the static key of the
+/// server. Clients pin the public key to open encrypted channels with the server.
+fn keygen(args: &[String]) -> Result<()> {
+    if args.len() != 2 {
+        bail!("Usage: chat-server keygen <private key file> <public key file>");
+    }
+
+    let (private, public) = context::generate_keys();
+    write_key_file(Path::new(&args[0]), &private.to_hex(), true)?;
+    write_key_file(Path::new(&args[1]), &public.to_hex(), false)?;
+
+    println!(
+        "Wrote the private key to {}, keep it secret. Give {} to the clients.",
+        args[0], args[1]
+    );
+    Ok(())
+}
+
+/// Write a key to a new file, that only the owner can read if it's private.
+fn write_key_file(path: &Path, key: &str, private: bool) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(if private { 0o600 } else { 0o644 });
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+
+    let mut file = options
+        .open(path)
+        .map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e))?;
+    writeln!(file, "{}", key)?;
+    Ok(())
+}
+
+/// The flags of `chat-server`.
+fn server_options(args: &mut Args) -> Result<(NetworkOptions, Option<PrivateKey>)> {
+    let options = NetworkOptions::from_args(args)?;
+    let private_key = args
+        .value::<PathBuf>("private-key")?
+        .map(|path| read_key_file(&path))
+        .transpose()?;
+    args.finish()?;
+    Ok((options, private_key))
 }
 
 #[tokio::main]
 async fn main() {
-    // Get the local address.
-    let args: Vec<_> = env::args().collect();
+    let args = Args::parse(env::args().skip(1));
 
-    if args.len() != 2 {
+    let result = match args.positional.first().map(String::as_str) {
+        Some("export") => args
+            .finish()
+            .and_then(|_| export_history(&args.positional[1..])),
+        Some("import") => args
+            .finish()
+            .and_then(|_| import_history(&args.positional[1..])),
+        Some("keygen") => args.finish().and_then(|_| keygen(&args.positional[1..])),
+        _ => return serve(args).await,
+    };
+
+    if let Err(e) = result {
+        eprintln!("{:?}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn serve(mut args: Args) {
+    let (options, private_key) = match server_options(&mut args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    // Get the local address.
+    let args = args.positional;
+    if args.len() != 1 && args.len() != 2 {
         eprintln!(
-            "You must provide only 1 argument: <local IPv6 address and port. Ex: [::1]:8080>"
+            "You must provide 1 or 2 arguments: <local address and port. Ex: [::1]:8080 or 0.0.0.0:8080> [data directory] {} [--private-key=<path>]",
+            NetworkOptions::USAGE
         );
         return;
     }
 
-    let node_address = match parse_address(&args[1]) {
+    let node_address = match parse_address(&args[0]) {
         Ok(a) => a,
         Err(e) => {
             eprintln!("Failed to get the local address: {:?}", e);
@@ -42,24 +163,74 @@ async fn main() {
         }
     };
 
+    // Rebuild the chat from the data directory, if there is one.
+    let (storage, chat) = match args.get(1) {
+        Some(dir) => match Storage::open(Path::new(dir)) {
+            Ok((storage, mut chat)) => {
+                let storage = Arc::new(Mutex::new(storage));
+                chat.set_journal(storage.clone());
+                (Some(storage), chat)
+            }
+            Err(e) => {
+                eprintln!("Failed to open the data directory: {:?}", e);
+                return;
+            }
+        },
+        None => (None, ChatApp::new()),
+    };
+
     // Construct the server.
-    let mut node = Server::new(ChatApp::new());
+    let mut node = Server::new(chat);
 
     // Construct the context.
-    let mut ctx = Ctx::new(node_address.id()).await;
+    let mut ctx = match Ctx::with_transport(node_address.id(), options.transport.clone()).await {
+        Ok(ctx) => ctx,
+        Err(e) => {
+            eprintln!("Failed to bind the port: {}", e);
+            std::process::exit(1);
+        }
+    };
+    options.apply(&mut ctx);
+    if let Some(key) = private_key {
+        ctx.set_private_key(key);
+    }
+    if let Some(storage) = &storage {
+        ctx.set_synced(storage.lock().unwrap().synced());
+    }
+    let dropped = ctx.dropped();
     let mut event_stream = ctx.event_stream().boxed().fuse();
     let mut ctx = ds_libs::Context::new(node_address, &mut ctx);
 
     // Init the server.
     node.init(&mut ctx);
 
-    while let Some(event) = event_stream.next().await {
-        match event {
-            // Servers don't handle Responses or ResendTimers.
-            context::Event::Response(_) | context::Event::ResendTimer(_) => {}
-            context::Event::Request(req) => {
-                node.handle_message(&mut ctx, req);
-            }
+    let mut snapshot_timer = interval(SNAPSHOT_INTERVAL);
+    let mut reported_drops = 0;
+
+    loop {
+        select! {
+            event = event_stream.select_next_some() => {
+                match event {
+                    // Servers don't handle Responses, ResendTimers or Handshakes.
+                    context::Event::Response(_)
+                    | context::Event::ResendTimer(_)
+                    | context::Event::Handshake(_) => {}
+                    context::Event::Request(req) => {
+                        node.handle_message(&mut ctx, req);
+                    }
+                }
+            },
+            _ = snapshot_timer.tick().fuse() => {
+                // Taken by the chat with the next command, it's the only one with the chat.
+                if let Some(storage) = &storage {
+                    storage.lock().unwrap().request_snapshot();
+                }
+
+                if dropped.total() != reported_drops {
+                    reported_drops = dropped.total();
+                    eprintln!("Dropped datagrams so far: {}", dropped);
+                }
+            },
         }
     }
 }
@@ -0,0 +1,271 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BTreeMap,
+    fmt,
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{plugins::format_duration, Message};
+
+/// Clients that haven't sent a heartbeat for this long are no longer counted as connected.
+pub const PRESENCE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The number of senders and hours listed in [ChatStats].
+const MAX_LISTED: usize = 5;
+/// The most senders that messages are counted for. Past this, the sender with the fewest messages
+/// is forgotten to make room for a new one.
+const MAX_SENDERS: usize = 1024;
+/// The most clients that are kept track of. Past this, the client that was seen the longest ago
+/// is forgotten to make room for a new one.
+const MAX_CONNECTED: usize = 1024;
+
+/// Counts of every message ever posted, not just the ones that are still in the history. These
+/// are rebuilt along with the messages when the chat is replayed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub(crate) struct Activity {
+    total: u64,
+    senders: BTreeMap<String, u64>,
+    /// The number of messages sent in each hour of the day, in UTC.
+    hours: [u64; 24],
+}
+
+impl Activity {
+    /// Count the message, at the time the server got it.
+    pub(crate) fn count(&mut self, message: &Message, time: DateTime<Utc>) {
+        self.total += 1;
+        if !self.senders.contains_key(&message.sender) && self.senders.len() >= MAX_SENDERS {
+            let quietest = self
+                .senders
+                .iter()
+                .min_by_key(|(_, &count)| count)
+                .map(|(sender, _)| sender.clone());
+            if let Some(quietest) = quietest {
+                self.senders.remove(&quietest);
+            }
+        }
+        *self.senders.entry(message.sender.clone()).or_insert(0) += 1;
+        self.hours[time.hour() as usize] += 1;
+    }
+}
+
+/// Who is connected and how long the server has been up. This only lasts while the server runs,
+/// so it is never saved and it is ignored when chats are compared.
+#[derive(Debug, Clone)]
+pub(crate) struct Presence {
+    started: Instant,
+    /// The session that each client is connected with, and when it was last seen.
+    last_seen: BTreeMap<String, (u64, Instant)>,
+}
+
+impl Presence {
+    /// Record that the client is connected in the session. Returns true if it wasn't connected
+    /// before.
+    pub(crate) fn heartbeat(&mut self, name: String, session: u64) -> bool {
+        if !self.last_seen.contains_key(&name) && self.last_seen.len() >= MAX_CONNECTED {
+            let oldest = self
+                .last_seen
+                .iter()
+                .min_by_key(|(_, &(_, seen))| seen)
+                .map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {
+                self.last_seen.remove(&oldest);
+            }
+        }
+        self.last_seen
+            .insert(name, (session, Instant::now()))
+            .is_none()
+    }
+
+    /// The session that the client with the name is connected in, if it's connected.
+    pub(crate) fn session(&self, name: &str) -> Option<u64> {
+        self.last_seen.get(name).map(|&(session, _)| session)
+    }
+
+    /// Move a client to its new name, in the same session. It counts as seen now.
+    pub(crate) fn rename(&mut self, from: &str, to: String) {
+        if let Some((session, _)) = self.last_seen.remove(from) {
+            self.last_seen.insert(to, (session, Instant::now()));
+        }
+    }
+
+    /// Forget the clients that haven't sent a heartbeat within the [PRESENCE_TIMEOUT] and return
+    /// their names.
+    pub(crate) fn expire(&mut self) -> Vec<String> {
+        let expired: Vec<_> = self
+            .last_seen
+            .iter()
+            .filter(|(_, (_, seen))| seen.elapsed() >= PRESENCE_TIMEOUT)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &expired {
+            self.last_seen.remove(name);
+        }
+        expired
+    }
+
+    /// The names of the clients that sent a heartbeat within the [PRESENCE_TIMEOUT].
+    pub(crate) fn connected(&self) -> Vec<String> {
+        self.last_seen
+            .iter()
+            .filter(|(_, (_, seen))| seen.elapsed() < PRESENCE_TIMEOUT)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Presence {
+            started: Instant::now(),
+            last_seen: BTreeMap::new(),
+        }
+    }
+}
+
+impl PartialEq for Presence {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Presence {}
+
+impl PartialOrd for Presence {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Presence {
+    fn cmp(&self, _other: &Self) -> Ordering {
+        Ordering::Equal
+    }
+}
+
+impl Hash for Presence {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+/// Rough activity numbers for the chat.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ChatStats {
+    /// Every message ever posted, including the ones that were dropped from the history.
+    pub total_messages: u64,
+    /// The senders with the most messages and their counts, busiest first.
+    pub top_senders: Vec<(String, u64)>,
+    /// The hours of the day, in UTC, with the most messages and their counts, busiest first.
+    pub busiest_hours: Vec<(u32, u64)>,
+    /// How long the server has been running in seconds.
+    pub uptime: u64,
+    /// The names of the clients that are currently connected.
+    pub connected: Vec<String>,
+}
+
+impl ChatStats {
+    pub(crate) fn new(activity: &Activity, presence: &Presence) -> ChatStats {
+        let mut top_senders: Vec<_> = activity
+            .senders
+            .iter()
+            .map(|(sender, &count)| (sender.clone(), count))
+            .collect();
+        top_senders.sort_by_key(|(_, count)| Reverse(*count));
+        top_senders.truncate(MAX_LISTED);
+
+        let mut busiest_hours: Vec<_> = (0..24)
+            .map(|hour| (hour, activity.hours[hour as usize]))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        busiest_hours.sort_by_key(|(_, count)| Reverse(*count));
+        busiest_hours.truncate(MAX_LISTED);
+
+        ChatStats {
+            total_messages: activity.total,
+            top_senders,
+            busiest_hours,
+            uptime: presence.started.elapsed().as_secs(),
+            connected: presence.connected(),
+        }
+    }
+}
+
+impl fmt::Display for ChatStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Messages posted: {}", self.total_messages)?;
+        writeln!(f, "Uptime: {}", format_duration(self.uptime))?;
+        writeln!(
+            f,
+            "Connected ({}): {}",
+            self.connected.len(),
+            self.connected.join(", ")
+        )?;
+
+        writeln!(f, "Top senders:")?;
+        for (sender, count) in &self.top_senders {
+            writeln!(f, "  {}: {}", sender, count)?;
+        }
+
+        write!(f, "Busiest hours (UTC):")?;
+        for (hour, count) in &self.busiest_hours {
+            write!(f, "\n  {:02}:00: {}", hour, count)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    /// Count a message that the server got at the hour, whatever time the client says it sent it.
+    fn count(activity: &mut Activity, sender: &str, hour: u32) {
+        let message = Message {
+            sent_time: Utc.ymd(2021, 1, 1).and_hms(23 - hour, 0, 0),
+            ..Message::new(sender.to_string(), "text".to_string())
+        };
+        activity.count(&message, Utc.ymd(2021, 1, 1).and_hms(hour, 0, 0));
+    }
+
+    #[test]
+    fn counting_activity() {
+        let mut activity = Activity::default();
+        for (sender, hour) in &[("a", 9), ("b", 9), ("a", 13), ("a", 9)] {
+            count(&mut activity, sender, *hour);
+        }
+
+        let mut presence = Presence::default();
+        presence.heartbeat("b".to_string(), 0);
+
+        let stats = ChatStats::new(&activity, &presence);
+        assert_eq!(4, stats.total_messages);
+        assert_eq!(
+            vec![("a".to_string(), 3), ("b".to_string(), 1)],
+            stats.top_senders
+        );
+        assert_eq!(vec![(9, 3), (13, 1)], stats.busiest_hours);
+        assert_eq!(vec!["b".to_string()], stats.connected);
+    }
+
+    #[test]
+    fn limiting_names() {
+        let mut activity = Activity::default();
+        let mut presence = Presence::default();
+        count(&mut activity, "busy", 9);
+        count(&mut activity, "busy", 9);
+        for i in 0..=MAX_SENDERS.max(MAX_CONNECTED) {
+            count(&mut activity, &i.to_string(), 9);
+            presence.heartbeat(i.to_string(), 0);
+        }
+
+        // The busiest senders are kept, and the newest clients.
+        assert_eq!(MAX_SENDERS, activity.senders.len());
+        assert_eq!(Some(&2), activity.senders.get("busy"));
+        assert_eq!(MAX_CONNECTED, presence.last_seen.len());
+        assert_eq!(Some(0), presence.session(&MAX_CONNECTED.to_string()));
+    }
+}